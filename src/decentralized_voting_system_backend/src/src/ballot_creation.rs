@@ -1,93 +1,192 @@
-
-use ic_cdk::api::time;
-use ic_stable_structures::memory_manager::VirtualMemory;
-use ic_stable_structures::{StableBTreeMap, Storable, DefaultMemoryImpl, MemoryId};
-use std::collections::HashMap;
+use candid::{Decode, Encode};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::cell::RefCell;
-
-type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-thread_local! {
-    static BALLOTS: RefCell<StableBTreeMap<u64, Ballot, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
-}
+use crate::election::{
+    _get_election, caller_administered_election, validate_text, Election, ElectionStatus, VotingMethod,
+    MAX_CANDIDATE_LEN,
+};
+use crate::migration::ballots_indexed;
+use crate::storage::{next_id, Sequence, BALLOTS, BALLOTS_BY_ELECTION};
+use crate::Error;
 
+// a question on a referendum election, answered by picking one of its options. Every ballot is
+// open for the election's voting period, as each vote answers all of them at once.
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-pub struct Ballot {
-    id: u64,
-    election_id: u64,
-    options: Vec<String>,
-    start_time: u64,
-    end_time: u64,
+pub(crate) struct Ballot {
+    pub(crate) id: u64,
+    pub(crate) election_id: u64,
+    pub(crate) question: String,
+    pub(crate) options: Vec<String>,
 }
 
 impl Storable for Ballot {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
-}
 
-impl BoundedStorable for Ballot {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-// Function to create a new ballot
-pub fn create_ballot(election_id: u64, options: Vec<String>, start_time: u64, end_time: u64) -> Option<Ballot> {
-    let ballot_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
+// the longest question a ballot may ask, in bytes; its options are limited like candidate names
+const MAX_QUESTION_LEN: usize = 512;
+
+// the most options a ballot may offer
+const MAX_OPTIONS: usize = 100;
+
+// Function to create a new ballot on a draft referendum election
+#[ic_cdk::update]
+fn create_ballot(election_id: u64, question: String, options: Vec<String>) -> Result<Ballot, Error> {
+    let election = caller_administered_election(election_id)?;
+
+    if election.voting_method != VotingMethod::Referendum {
+        return Err(Error::InvalidPayload {
+            msg: format!("election with id={} is not a referendum", election_id),
+        });
+    }
+
+    if election.status != ElectionStatus::Draft {
+        return Err(Error::InvalidTransition {
+            msg: format!("election with id={} is no longer a draft", election_id),
+        });
+    }
+
+    validate_text("questions", &question, MAX_QUESTION_LEN)?;
+
+    if options.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "a ballot needs at least one option".to_string(),
+        });
+    }
+
+    if options.len() > MAX_OPTIONS {
+        return Err(Error::CapacityExceeded {
+            msg: format!("a ballot can offer at most {} options", MAX_OPTIONS),
+        });
+    }
+
+    for option in &options {
+        validate_text("options", option, MAX_CANDIDATE_LEN)?;
+    }
+
+    let ballot_id = next_id(Sequence::Ballot);
 
     let ballot = Ballot {
         id: ballot_id,
         election_id,
+        question,
         options,
-        start_time,
-        end_time,
     };
 
-    // Validate start and end times
-    if end_time > start_time {
-        do_insert_ballot(&ballot);
-        Some(ballot)
-    } else {
-        
-        None
-    }
+    do_insert_ballot(&ballot);
+    Ok(ballot)
 }
 
 // Function to get a ballot by ID
-pub fn get_ballot(ballot_id: u64) -> Option<Ballot> {
-    BALLOTS.with(|service| service.borrow().get(&ballot_id))
+#[ic_cdk::query]
+fn get_ballot(ballot_id: u64) -> Result<Ballot, Error> {
+    BALLOTS
+        .with(|service| service.borrow().get(&ballot_id))
+        .ok_or(Error::NotFound {
+            msg: format!("a ballot with id={} not found", ballot_id),
+        })
 }
 
 // Function to get all ballots for a specific election
-pub fn get_ballots_for_election(election_id: u64) -> Vec<Ballot> {
-    BALLOTS
-        .with(|service| {
+#[ic_cdk::query]
+fn get_ballots_for_election(election_id: u64) -> Result<Vec<Ballot>, Error> {
+    _get_election(&election_id).ok_or(Error::NotFound {
+        msg: format!("an election with id={} not found", election_id),
+    })?;
+    Ok(_get_ballots_for_election(election_id))
+}
+
+// a helper method to get the ballots of an election. used in get_ballots_for_election/get_ballot_results
+pub(crate) fn _get_ballots_for_election(election_id: u64) -> Vec<Ballot> {
+    // ballots created before the index are still being added to it after an upgrade
+    if !ballots_indexed() {
+        return BALLOTS.with(|service| {
             service
                 .borrow()
-                .values()
+                .iter()
+                .map(|(_, ballot)| ballot)
                 .filter(|ballot| ballot.election_id == election_id)
-                .cloned()
                 .collect::<Vec<_>>()
-        })
+        });
+    }
+
+    let ballot_ids: Vec<u64> = BALLOTS_BY_ELECTION.with(|service| {
+        service
+            .borrow()
+            .range((election_id, 0)..=(election_id, u64::MAX))
+            .map(|((_, ballot_id), _)| ballot_id)
+            .collect()
+    });
+    BALLOTS.with(|service| {
+        let service = service.borrow();
+        ballot_ids
+            .iter()
+            .filter_map(|ballot_id| service.get(ballot_id))
+            .collect()
+    })
+}
+
+// Check that a referendum vote answers every ballot of the election exactly once,
+// picking one of each ballot's options
+pub(crate) fn validate_answers(election: &Election, answers: &[(u64, String)]) -> Result<(), Error> {
+    let ballots = _get_ballots_for_election(election.id);
+
+    if ballots.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!("election with id={} has no ballots", election.id),
+        });
+    }
+
+    for ballot in &ballots {
+        let mut picked = answers.iter().filter(|(ballot_id, _)| *ballot_id == ballot.id);
+        match (picked.next(), picked.next()) {
+            (Some((_, option)), None) if ballot.options.contains(option) => {}
+            (Some((_, option)), None) => {
+                return Err(Error::InvalidPayload {
+                    msg: format!("'{}' is not an option on ballot with id={}", option, ballot.id),
+                })
+            }
+            (None, _) => {
+                return Err(Error::InvalidPayload {
+                    msg: format!("ballot with id={} is not answered", ballot.id),
+                })
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidPayload {
+                    msg: format!("ballot with id={} is answered more than once", ballot.id),
+                })
+            }
+        }
+    }
+
+    if let Some((ballot_id, _)) = answers
+        .iter()
+        .find(|(ballot_id, _)| !ballots.iter().any(|ballot| ballot.id == *ballot_id))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "ballot with id={} is not on election with id={}",
+                ballot_id, election.id
+            ),
+        });
+    }
+
+    Ok(())
 }
 
 // Helper method to perform insert for ballots
 fn do_insert_ballot(ballot: &Ballot) {
     BALLOTS.with(|service| service.borrow_mut().insert(ballot.id, ballot.clone()));
+    BALLOTS_BY_ELECTION.with(|service| service.borrow_mut().insert((ballot.election_id, ballot.id), ()));
 }
-
-
-
@@ -0,0 +1,377 @@
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::election::{
+    _get_election, get_elections_with_status, is_election_ended, validate_topic, Election, ElectionStatus,
+};
+use crate::storage::DELEGATIONS;
+use crate::user_management::{
+    _get_voter, _get_voter_by_principal, caller_voter, validate_eligible, voter_weight, Voter,
+};
+use crate::voting::{_get_voter_vote, has_voter_voted, Selection};
+use crate::Error;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum DelegationScope {
+    Election(u64),
+    // every election tagged with the topic
+    Topic(String),
+}
+
+// a voter's delegation in one scope. Keys sort by scope first, so the delegations in a scope can
+// be read as a range.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DelegationKey {
+    pub(crate) scope: DelegationScope,
+    pub(crate) voter_id: u64,
+}
+
+impl Storable for DelegationKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+// weight that reached a voter who voted directly through delegation chains
+#[derive(Clone, Copy, Default)]
+pub(crate) struct DelegatedWeight {
+    pub(crate) weight: u64,
+    pub(crate) voters: u64,
+}
+
+// delegates the caller's vote in an election, or in every election on a topic, to another voter.
+// A delegation in an election takes precedence over one for its topic, and voting directly
+// overrides both. Delegations on a topic cannot change while an election on it is closed and
+// waiting to be tallied.
+#[ic_cdk::update]
+fn delegate_vote(scope: DelegationScope, delegate: Principal) -> Result<(), Error> {
+    let voter = caller_voter()?;
+    validate_delegation_scope(&voter, &scope)?;
+
+    let delegate = _get_voter_by_principal(&delegate).ok_or(Error::NotFound {
+        msg: format!("no voter registered for principal {}", delegate),
+    })?;
+    if delegate.id == voter.id {
+        return Err(Error::InvalidPayload {
+            msg: "voters cannot delegate to themselves".to_string(),
+        });
+    }
+
+    let key = DelegationKey {
+        scope,
+        voter_id: voter.id,
+    };
+    DELEGATIONS.with(|service| service.borrow_mut().insert(key, delegate.id));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn revoke_delegation(scope: DelegationScope) -> Result<(), Error> {
+    let voter = caller_voter()?;
+    validate_delegation_scope(&voter, &scope)?;
+
+    let key = DelegationKey {
+        scope,
+        voter_id: voter.id,
+    };
+    match DELEGATIONS.with(|service| service.borrow_mut().remove(&key)) {
+        Some(_) => Ok(()),
+        None => Err(Error::NotFound {
+            msg: "no delegation found in the given scope".to_string(),
+        }),
+    }
+}
+
+// Check that a voter can still change their delegation in a scope
+fn validate_delegation_scope(voter: &Voter, scope: &DelegationScope) -> Result<(), Error> {
+    match scope {
+        DelegationScope::Election(election_id) => {
+            let election = _get_election(election_id).ok_or(Error::NotFound {
+                msg: format!("an election with id={} not found", election_id),
+            })?;
+            validate_eligible(*election_id, voter)?;
+            if is_election_ended(&election) || election.status == ElectionStatus::Cancelled {
+                return Err(Error::VotingClosed {
+                    msg: format!("election with id={} is no longer open", election_id),
+                });
+            }
+            if has_voter_voted(*election_id, voter.id) {
+                return Err(Error::AlreadyVoted {
+                    msg: format!(
+                        "voter with id={} has already voted in election with id={}",
+                        voter.id, election_id
+                    ),
+                });
+            }
+            Ok(())
+        }
+        DelegationScope::Topic(topic) => {
+            validate_topic(topic)?;
+            // an election that closed is tallied with the delegations it closed with
+            let closed = get_elections_with_status(ElectionStatus::Closed);
+            match closed.iter().find(|election| election.topic.as_ref() == Some(topic)) {
+                Some(election) => Err(Error::VotingClosed {
+                    msg: format!(
+                        "election with id={} on topic '{}' has closed and is not tallied yet",
+                        election.id, topic
+                    ),
+                }),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+// follows the delegation chain of every eligible voter who did not vote directly, returning
+// the weight that reached each voter who did. Chains that loop, or end at a voter who did not
+// vote, are not counted.
+pub(crate) fn resolve_delegations(election: &Election) -> std::collections::HashMap<u64, DelegatedWeight> {
+    let mut arrived = std::collections::HashMap::<u64, DelegatedWeight>::new();
+    let mut next = Some(first_delegation(election));
+    while let Some(from) = next {
+        let (resolved, after) = resolve_delegations_from(election, from, usize::MAX);
+        for (recipient, weight) in resolved {
+            let delegated = arrived.entry(recipient).or_default();
+            delegated.weight += weight;
+            delegated.voters += 1;
+        }
+        next = after;
+    }
+    arrived
+}
+
+// where resolving the delegations of an election starts: its own delegations, then its topic's
+pub(crate) fn first_delegation(election: &Election) -> DelegationKey {
+    DelegationKey {
+        scope: DelegationScope::Election(election.id),
+        voter_id: 0,
+    }
+}
+
+// follows the chains of up to `limit` delegations of an election from the key `from` on,
+// returning the (recipient, weight) of each that reached a voter, and the key to resume at
+pub(crate) fn resolve_delegations_from(
+    election: &Election,
+    from: DelegationKey,
+    limit: usize,
+) -> (Vec<(u64, u64)>, Option<DelegationKey>) {
+    let scope = from.scope.clone();
+    let last = DelegationKey {
+        scope: scope.clone(),
+        voter_id: u64::MAX,
+    };
+    let keys: Vec<DelegationKey> = DELEGATIONS.with(|service| {
+        service
+            .borrow()
+            .range(from..=last)
+            .take(limit.saturating_add(1))
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    let resolved = keys
+        .iter()
+        .take(limit)
+        // a delegation in the election takes precedence over one for its topic
+        .filter(|key| {
+            matches!(key.scope, DelegationScope::Election(_))
+                || delegation_in(&DelegationScope::Election(election.id), key.voter_id).is_none()
+        })
+        .filter_map(|key| {
+            let recipient = resolve_delegator(election, key.voter_id)?;
+            Some((recipient, voter_weight(election.id, key.voter_id)))
+        })
+        .collect();
+
+    let next = match (keys.get(limit), scope, &election.topic) {
+        (Some(key), _, _) => Some(key.clone()),
+        (None, DelegationScope::Election(_), Some(topic)) => Some(DelegationKey {
+            scope: DelegationScope::Topic(topic.clone()),
+            voter_id: 0,
+        }),
+        _ => None,
+    };
+    (resolved, next)
+}
+
+// the voter a delegator's delegation chain reaches, if the delegator is eligible, did not vote
+// directly, and the chain ends at a voter who voted without looping
+fn resolve_delegator(election: &Election, delegator: u64) -> Option<u64> {
+    let eligible = _get_voter(&delegator).is_some_and(|voter| validate_eligible(election.id, &voter).is_ok());
+    if !eligible || has_voter_voted(election.id, delegator) {
+        return None;
+    }
+
+    let mut visited = std::collections::HashSet::from([delegator]);
+    let mut current = delegator;
+    loop {
+        match delegate_of(election, current) {
+            Some(next) if has_voter_voted(election.id, next) => return Some(next),
+            Some(next) if visited.insert(next) => current = next,
+            // a cycle, or a chain ending at a voter who did not vote
+            _ => return None,
+        }
+    }
+}
+
+// whom a voter delegates to in an election, by their delegation in the election or else for its topic
+fn delegate_of(election: &Election, voter_id: u64) -> Option<u64> {
+    delegation_in(&DelegationScope::Election(election.id), voter_id).or_else(|| {
+        let topic = election.topic.clone()?;
+        delegation_in(&DelegationScope::Topic(topic), voter_id)
+    })
+}
+
+fn delegation_in(scope: &DelegationScope, voter_id: u64) -> Option<u64> {
+    let key = DelegationKey {
+        scope: scope.clone(),
+        voter_id,
+    };
+    DELEGATIONS.with(|service| service.borrow().get(&key))
+}
+
+// the rankings of the voters that delegated weight arrived at, each with that weight, so ranked
+// tabulations count it on the delegate's ballot
+pub(crate) fn delegated_rankings(election: &Election) -> Vec<(Vec<String>, u64)> {
+    resolve_delegations(election)
+        .into_iter()
+        .filter_map(
+            |(recipient, delegated)| match _get_voter_vote(election.id, recipient)?.selection {
+                Selection::Ranking(ranking) => Some((ranking, delegated.weight)),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{VOTERS, VOTES_BY_VOTER};
+
+    const ELECTION: u64 = 1;
+
+    fn election() -> Election {
+        Election {
+            id: ELECTION,
+            topic: Some("budget".to_string()),
+            ..Election::default()
+        }
+    }
+
+    fn register(voter_ids: &[u64]) {
+        for &id in voter_ids {
+            let voter = Voter {
+                id,
+                principal: Principal::from_slice(&[id as u8]),
+                username: format!("voter{}", id),
+                registered_elections: vec![ELECTION],
+            };
+            VOTERS.with(|service| service.borrow_mut().insert(id, voter));
+        }
+    }
+
+    fn vote(voter_id: u64) {
+        VOTES_BY_VOTER.with(|service| service.borrow_mut().insert((ELECTION, voter_id), voter_id));
+    }
+
+    fn delegate(scope: DelegationScope, voter_id: u64, delegate_id: u64) {
+        DELEGATIONS.with(|service| {
+            service
+                .borrow_mut()
+                .insert(DelegationKey { scope, voter_id }, delegate_id)
+        });
+    }
+
+    // (recipient, weight, voters) for every voter delegated weight arrived at
+    fn resolved() -> Vec<(u64, u64, u64)> {
+        let mut arrived: Vec<(u64, u64, u64)> = resolve_delegations(&election())
+            .into_iter()
+            .map(|(recipient, delegated)| (recipient, delegated.weight, delegated.voters))
+            .collect();
+        arrived.sort();
+        arrived
+    }
+
+    #[test]
+    fn resolve_delegations_drops_chains_that_loop() {
+        register(&[1, 2, 3, 4, 5]);
+        vote(4);
+        delegate(DelegationScope::Election(ELECTION), 1, 2);
+        delegate(DelegationScope::Election(ELECTION), 2, 3);
+        delegate(DelegationScope::Election(ELECTION), 3, 2);
+        delegate(DelegationScope::Election(ELECTION), 5, 4);
+
+        assert_eq!(resolved(), vec![(4, 1, 1)]);
+    }
+
+    #[test]
+    fn resolve_delegations_follows_chains_to_a_voter_and_drops_those_ending_at_a_non_voter() {
+        register(&[1, 2, 3, 4, 5]);
+        vote(3);
+        // 1 reaches 3 through 2, who did not vote either
+        delegate(DelegationScope::Election(ELECTION), 1, 2);
+        delegate(DelegationScope::Election(ELECTION), 2, 3);
+        // 4's delegate 5 neither voted nor delegated
+        delegate(DelegationScope::Election(ELECTION), 4, 5);
+
+        assert_eq!(resolved(), vec![(3, 2, 2)]);
+    }
+
+    #[test]
+    fn resolve_delegations_lets_a_direct_vote_override_the_voters_delegation() {
+        register(&[1, 2]);
+        vote(1);
+        vote(2);
+        delegate(DelegationScope::Election(ELECTION), 1, 2);
+
+        assert!(resolved().is_empty());
+    }
+
+    #[test]
+    fn resolve_delegations_prefers_the_election_delegation_over_the_topic_delegation() {
+        register(&[1, 2, 3, 4]);
+        vote(2);
+        vote(3);
+        delegate(DelegationScope::Topic("budget".to_string()), 1, 2);
+        delegate(DelegationScope::Election(ELECTION), 1, 3);
+        // a topic delegation still counts for a voter without one in the election
+        delegate(DelegationScope::Topic("budget".to_string()), 4, 2);
+
+        assert_eq!(resolved(), vec![(2, 1, 1), (3, 1, 1)]);
+    }
+
+    #[test]
+    fn resolve_delegations_from_resumes_across_chunks_and_moves_on_to_the_topic() {
+        register(&[1, 2, 3, 4, 5]);
+        vote(5);
+        delegate(DelegationScope::Election(ELECTION), 1, 5);
+        delegate(DelegationScope::Election(ELECTION), 2, 1);
+        // 3 is resolved with the election delegations only, 4 with the topic delegations
+        delegate(DelegationScope::Election(ELECTION), 3, 5);
+        delegate(DelegationScope::Topic("budget".to_string()), 3, 1);
+        delegate(DelegationScope::Topic("budget".to_string()), 4, 3);
+
+        let mut chunks = Vec::new();
+        let mut next = Some(first_delegation(&election()));
+        while let Some(from) = next {
+            let (resolved, after) = resolve_delegations_from(&election(), from, 2);
+            chunks.push(resolved);
+            next = after;
+        }
+        assert_eq!(chunks, vec![vec![(5, 1), (5, 1)], vec![(5, 1)], vec![(5, 1)]]);
+        assert_eq!(resolved(), vec![(5, 4, 4)]);
+    }
+}
@@ -0,0 +1,491 @@
+use candid::Decode;
+use ic_cdk::api::time;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::migration::{decode_record, encode_record, unknown_version, Versioned};
+use crate::result::{init_running_tallies, TallySummary};
+use crate::storage::{next_id, Sequence, ELECTIONS};
+use crate::user_management::{caller_principal, do_insert_role, is_election_admin, is_owner, Role};
+use crate::Error;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Election {
+    pub(crate) id: u64,
+    pub(crate) title: String,
+    pub(crate) candidates: Vec<String>,
+    pub(crate) start_time: u64,
+    pub(crate) end_time: u64,
+    // accept votes for candidates not listed above, tallied separately
+    pub(crate) allow_write_ins: bool,
+    pub(crate) voting_method: VotingMethod,
+    // number of candidates to elect
+    pub(crate) seats: u32,
+    // highest score a ballot may give in score elections
+    pub(crate) max_score: u32,
+    // credits each registered voter receives in quadratic elections
+    pub(crate) voice_credits: u64,
+    // voters may delegate for every election sharing a topic
+    pub(crate) topic: Option<String>,
+    // how ties are decided when the election is tallied, for the last places and in the rounds of
+    // its tabulation; None leaves a tie for the last places reported and breaks the others by
+    // listing order
+    pub(crate) tie_break: Option<TieBreak>,
+    pub(crate) status: ElectionStatus,
+    // what the outcome stored by the tally job decided, set once the election is Tallied
+    pub(crate) tally: Option<TallySummary>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub(crate) enum VotingMethod {
+    // one candidate per ballot, most votes wins
+    #[default]
+    Plurality,
+    // ranked ballots tabulated by instant runoff
+    RankedChoice,
+    // any number of candidates per ballot, most approvals wins
+    Approval,
+    // ranked ballots filling several seats by single transferable vote
+    SingleTransferableVote,
+    // ranked ballots scoring points by position, most points wins
+    Borda,
+    // every candidate rated from 0 to max_score, highest total wins
+    Score,
+    // registered voters spread voice_credits across candidates at quadratic cost
+    Quadratic,
+    // one answer to each question on the election's ballots
+    Referendum,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum TieBreak {
+    // the tied candidate whose last vote was cast first, having reached the tied total first; in
+    // an elimination, the one whose last vote was cast last. A ranked ballot counts as a vote for
+    // every candidate it ranks, whether or not it reached them in the tied round.
+    EarliestVote,
+    // an admin draws lots outside the canister and records the drawn candidates
+    LotDraw,
+    // candidates drawn with randomness from the management canister's raw_rand
+    Random,
+    // the tied places are left to a runoff election; ties in the rounds of an instant runoff or
+    // STV tabulation, which cannot wait for one, are broken by listing order
+    Runoff,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub(crate) enum ElectionStatus {
+    // being prepared by its admins, not visible to voters
+    #[default]
+    Draft,
+    // voters may register until start_time
+    RegistrationOpen,
+    // between start_time and end_time
+    VotingOpen,
+    // end_time has passed or an admin closed voting early
+    Closed,
+    Tallied,
+    Certified,
+    Cancelled,
+}
+
+impl ElectionStatus {
+    pub(crate) fn can_transition_to(self, next: ElectionStatus) -> bool {
+        use ElectionStatus::*;
+        matches!(
+            (self, next),
+            (Draft, RegistrationOpen)
+                | (RegistrationOpen, VotingOpen)
+                | (VotingOpen, Closed)
+                | (Closed, Tallied)
+                | (Tallied, Certified)
+                | (Draft | RegistrationOpen | VotingOpen, Cancelled)
+        )
+    }
+}
+
+// applies the transitions driven by the clock to the stored status
+fn with_current_status(mut election: Election) -> Election {
+    let current_time = time();
+    if election.status == ElectionStatus::RegistrationOpen && current_time >= election.start_time {
+        election.status = ElectionStatus::VotingOpen;
+    }
+    if election.status == ElectionStatus::VotingOpen && current_time > election.end_time {
+        election.status = ElectionStatus::Closed;
+    }
+    election
+}
+
+impl Storable for Election {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// an election as stored before schema versioning, a plurality vote open between its times
+#[derive(candid::CandidType, Deserialize)]
+struct ElectionV0 {
+    id: u64,
+    title: String,
+    candidates: Vec<String>,
+    start_time: u64,
+    end_time: u64,
+}
+
+impl Versioned for Election {
+    fn migrate(version: u8, payload: &[u8]) -> Self {
+        match version {
+            0 => {
+                let election = Decode!(payload, ElectionV0).unwrap();
+                // the clock moves it on to VotingOpen and Closed, as the times used to
+                Election {
+                    id: election.id,
+                    title: election.title,
+                    candidates: election.candidates,
+                    start_time: election.start_time,
+                    end_time: election.end_time,
+                    seats: 1,
+                    status: ElectionStatus::RegistrationOpen,
+                    ..Default::default()
+                }
+            }
+            _ => unknown_version(version),
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ElectionPayload {
+    pub(crate) title: String,
+    pub(crate) candidates: Vec<String>,
+    pub(crate) start_time: u64,
+    pub(crate) end_time: u64,
+    pub(crate) allow_write_ins: bool,
+    pub(crate) voting_method: VotingMethod,
+    pub(crate) seats: u32,
+    pub(crate) max_score: u32,
+    pub(crate) voice_credits: u64,
+    pub(crate) topic: Option<String>,
+    pub(crate) tie_break: Option<TieBreak>,
+}
+
+// Check the election settings an admin may choose
+fn validate_election_payload(payload: &ElectionPayload) -> Result<(), Error> {
+    if payload.end_time <= payload.start_time {
+        return Err(Error::InvalidPayload {
+            msg: "an election must end after it starts".to_string(),
+        });
+    }
+
+    validate_text("titles", &payload.title, MAX_TITLE_LEN)?;
+
+    if payload.candidates.len() > MAX_CANDIDATES {
+        return Err(Error::CapacityExceeded {
+            msg: format!("an election can list at most {} candidates", MAX_CANDIDATES),
+        });
+    }
+
+    for (position, candidate) in payload.candidates.iter().enumerate() {
+        validate_text("candidate names", candidate, MAX_CANDIDATE_LEN)?;
+        if payload.candidates[..position].contains(candidate) {
+            return Err(Error::InvalidPayload {
+                msg: format!("'{}' is listed more than once", candidate),
+            });
+        }
+    }
+
+    if payload.allow_write_ins && payload.voting_method != VotingMethod::Plurality {
+        return Err(Error::InvalidPayload {
+            msg: "write-ins are only supported in plurality elections".to_string(),
+        });
+    }
+
+    if payload.seats == 0 || payload.seats as usize > payload.candidates.len().max(1) {
+        return Err(Error::InvalidPayload {
+            msg: "seats must be between 1 and the number of candidates".to_string(),
+        });
+    }
+
+    if payload.voting_method == VotingMethod::Score && payload.max_score == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "score elections need a max_score of at least 1".to_string(),
+        });
+    }
+
+    if payload.voting_method == VotingMethod::Quadratic && payload.voice_credits == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "quadratic elections need at least 1 voice credit per voter".to_string(),
+        });
+    }
+
+    if let Some(topic) = &payload.topic {
+        validate_topic(topic)?;
+    }
+
+    if payload.seats > 1 && payload.voting_method != VotingMethod::SingleTransferableVote {
+        return Err(Error::InvalidPayload {
+            msg: "only single transferable vote elections can fill several seats".to_string(),
+        });
+    }
+
+    // referendums have no candidates to tie
+    if payload.tie_break.is_some() && payload.voting_method == VotingMethod::Referendum {
+        return Err(Error::InvalidPayload {
+            msg: format!("{:?} elections do not take a tie-break policy", payload.voting_method),
+        });
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_election(payload: ElectionPayload) -> Result<Election, Error> {
+    let caller = caller_principal()?;
+    if !is_owner(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only owners can create elections".to_string(),
+        });
+    }
+
+    validate_election_payload(&payload)?;
+
+    let election_id = next_id(Sequence::Election);
+
+    let election = Election {
+        id: election_id,
+        title: payload.title,
+        candidates: payload.candidates,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        allow_write_ins: payload.allow_write_ins,
+        voting_method: payload.voting_method,
+        seats: payload.seats,
+        max_score: payload.max_score,
+        voice_credits: payload.voice_credits,
+        topic: payload.topic,
+        tie_break: payload.tie_break,
+        status: ElectionStatus::Draft,
+        tally: None,
+    };
+
+    do_insert_election(&election);
+    init_running_tallies(election_id);
+    // the creator administers the election they created
+    do_insert_role(caller, Role::ElectionAdmin, Some(election_id), caller);
+    Ok(election)
+}
+
+#[ic_cdk::update]
+fn update_election(election_id: u64, payload: ElectionPayload) -> Result<Election, Error> {
+    let election = caller_administered_election(election_id)?;
+
+    // Only drafts can be edited
+    if election.status != ElectionStatus::Draft {
+        return Err(Error::InvalidTransition {
+            msg: format!("election with id={} is no longer a draft", election_id),
+        });
+    }
+
+    validate_election_payload(&payload)?;
+
+    let updated_election = Election {
+        title: payload.title,
+        candidates: payload.candidates,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        allow_write_ins: payload.allow_write_ins,
+        voting_method: payload.voting_method,
+        seats: payload.seats,
+        max_score: payload.max_score,
+        voice_credits: payload.voice_credits,
+        topic: payload.topic,
+        tie_break: payload.tie_break,
+        ..election
+    };
+
+    do_insert_election(&updated_election);
+    Ok(updated_election)
+}
+
+#[ic_cdk::update]
+fn open_registration(election_id: u64) -> Result<Election, Error> {
+    let election = caller_administered_election(election_id)?;
+
+    if election.start_time <= time() {
+        return Err(Error::InvalidPayload {
+            msg: format!("election with id={} must start in the future", election_id),
+        });
+    }
+
+    transition_election(election, ElectionStatus::RegistrationOpen)
+}
+
+// ends voting before the scheduled end_time
+#[ic_cdk::update]
+fn close_election(election_id: u64) -> Result<Election, Error> {
+    let mut election = caller_administered_election(election_id)?;
+    election.end_time = election.end_time.min(time());
+    transition_election(election, ElectionStatus::Closed)
+}
+
+#[ic_cdk::update]
+fn extend_election(election_id: u64, end_time: u64) -> Result<Election, Error> {
+    let mut election = caller_administered_election(election_id)?;
+
+    if !matches!(
+        election.status,
+        ElectionStatus::Draft | ElectionStatus::RegistrationOpen | ElectionStatus::VotingOpen
+    ) {
+        return Err(Error::InvalidTransition {
+            msg: format!("election with id={} can no longer be extended", election_id),
+        });
+    }
+
+    if end_time <= election.end_time {
+        return Err(Error::InvalidPayload {
+            msg: "an extension must move the end time later".to_string(),
+        });
+    }
+
+    election.end_time = end_time;
+    do_insert_election(&election);
+    Ok(election)
+}
+
+#[ic_cdk::update]
+fn certify_election(election_id: u64) -> Result<Election, Error> {
+    let election = caller_administered_election(election_id)?;
+    transition_election(election, ElectionStatus::Certified)
+}
+
+#[ic_cdk::update]
+fn cancel_election(election_id: u64) -> Result<Election, Error> {
+    let election = caller_administered_election(election_id)?;
+    transition_election(election, ElectionStatus::Cancelled)
+}
+
+// moves an election to the next status if the lifecycle allows it
+fn transition_election(mut election: Election, next: ElectionStatus) -> Result<Election, Error> {
+    if !election.status.can_transition_to(next) {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "election with id={} cannot move from {:?} to {:?}",
+                election.id, election.status, next
+            ),
+        });
+    }
+
+    election.status = next;
+    do_insert_election(&election);
+    Ok(election)
+}
+
+// loads an election, checking that the caller administers it.
+pub(crate) fn caller_administered_election(election_id: u64) -> Result<Election, Error> {
+    let caller = caller_principal()?;
+
+    let election = _get_election(&election_id).ok_or(Error::NotFound {
+        msg: format!("an election with id={} not found", election_id),
+    })?;
+
+    if is_election_admin(&caller, election_id) {
+        Ok(election)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("caller is not an admin of election with id={}", election_id),
+        })
+    }
+}
+
+// helper method to perform insert for elections.
+pub(crate) fn do_insert_election(election: &Election) {
+    ELECTIONS.with(|service| service.borrow_mut().insert(election.id, election.clone()));
+}
+
+// Check if an election is ongoing
+pub(crate) fn is_election_ongoing(election_id: u64) -> bool {
+    match _get_election(&election_id) {
+        Some(election) => election.status == ElectionStatus::VotingOpen,
+        None => false,
+    }
+}
+
+#[ic_cdk::query]
+fn get_election(election_id: u64) -> Result<Election, Error> {
+    match _get_election(&election_id) {
+        Some(election) => Ok(election),
+        None => Err(Error::NotFound {
+            msg: format!("an election with id={} not found", election_id),
+        }),
+    }
+}
+
+// a helper method to get an election by id. used in get_election/get_election_results
+pub(crate) fn _get_election(id: &u64) -> Option<Election> {
+    ELECTIONS
+        .with(|service| service.borrow().get(id))
+        .map(with_current_status)
+}
+
+// Check if an election has ended
+pub(crate) fn is_election_ended(election: &Election) -> bool {
+    matches!(
+        election.status,
+        ElectionStatus::Closed | ElectionStatus::Tallied | ElectionStatus::Certified
+    )
+}
+
+#[ic_cdk::query]
+fn get_ongoing_elections() -> Result<Vec<Election>, Error> {
+    Ok(get_elections_with_status(ElectionStatus::VotingOpen))
+}
+
+#[ic_cdk::query]
+fn get_available_elections() -> Result<Vec<Election>, Error> {
+    Ok(get_elections_with_status(ElectionStatus::RegistrationOpen))
+}
+
+pub(crate) fn get_elections_with_status(status: ElectionStatus) -> Vec<Election> {
+    ELECTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, election)| with_current_status(election))
+            .filter(|election| election.status == status)
+            .collect::<Vec<_>>()
+    })
+}
+
+// the longest title, candidate name and topic an election may have, in bytes
+const MAX_TITLE_LEN: usize = 256;
+pub(crate) const MAX_CANDIDATE_LEN: usize = 128;
+const MAX_TOPIC_LEN: usize = 64;
+
+// the most candidates an election may list
+const MAX_CANDIDATES: usize = 1000;
+
+pub(crate) fn validate_topic(topic: &str) -> Result<(), Error> {
+    validate_text("topics", topic, MAX_TOPIC_LEN)
+}
+
+// rejects an empty text field or one longer than max_len bytes, naming the field in the errors
+pub(crate) fn validate_text(field: &str, text: &str, max_len: usize) -> Result<(), Error> {
+    if text.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} cannot be empty", field),
+        });
+    }
+    if text.len() > max_len {
+        return Err(Error::CapacityExceeded {
+            msg: format!("{} can be at most {} bytes long", field, max_len),
+        });
+    }
+    Ok(())
+}
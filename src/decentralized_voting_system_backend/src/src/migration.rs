@@ -0,0 +1,485 @@
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use crate::storage::{
+    next_vote_id, Memory, Sequence, BALLOTS, BALLOTS_BY_ELECTION, ELECTIONS, ID_COUNTER, ID_SEQUENCES, MIGRATION,
+    VOTERS, VOTES_BY_ELECTION, VOTES_STORAGE, VOTE_ID_BIT, VOTE_ID_KEY,
+};
+use crate::voting::{do_insert_vote, do_remove_vote, Vote};
+
+// the schema version records are written with. Bump it when a stored record changes shape and
+// teach that record's `migrate` to read the previous version. Versions must stay below b'D'
+// so they cannot be mistaken for the start of an unversioned record.
+pub(crate) const SCHEMA_VERSION: u8 = 1;
+
+// records written before versioning are bare Candid, which always starts with this magic
+const CANDID_MAGIC: &[u8] = b"DIDL";
+
+// records rewritten per timer callback by the batch migration
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+// a stored record that can be read back from any earlier schema version
+pub(crate) trait Versioned: CandidType + DeserializeOwned {
+    // rebuilds the record from the Candid payload written by an older schema version
+    fn migrate(version: u8, payload: &[u8]) -> Self;
+}
+
+// prefixes the record's Candid encoding with the current schema version
+pub(crate) fn encode_record<T: Versioned>(record: &T) -> Vec<u8> {
+    let mut bytes = vec![SCHEMA_VERSION];
+    bytes.extend(Encode!(record).unwrap());
+    bytes
+}
+
+// decodes a stored record, migrating it if an older schema version wrote it
+pub(crate) fn decode_record<T: Versioned>(bytes: &[u8]) -> T {
+    let (version, payload) = match bytes {
+        _ if bytes.starts_with(CANDID_MAGIC) => (0, bytes),
+        [version, payload @ ..] => (*version, payload),
+        [] => ic_cdk::trap("cannot decode an empty record"),
+    };
+    match version.cmp(&SCHEMA_VERSION) {
+        Ordering::Equal => Decode!(payload, T).unwrap(),
+        Ordering::Less => T::migrate(version, payload),
+        Ordering::Greater => ic_cdk::trap(&format!(
+            "record written by schema version {} but this code only reads up to {}",
+            version, SCHEMA_VERSION
+        )),
+    }
+}
+
+// for `migrate` arms of versions a record never had
+pub(crate) fn unknown_version(version: u8) -> ! {
+    ic_cdk::trap(&format!("no migration from schema version {}", version))
+}
+
+// the progress of the batch migration, kept in stable memory. It is stored as plain Candid, so
+// any field added later must be an Option.
+#[derive(CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct MigrationState {
+    // the schema version of the code that last ran, saved before every upgrade
+    code_version: u8,
+    // the schema version every stored record has been rewritten with
+    migrated_version: u8,
+    // where the running batch migration resumes: an index into the migrated maps and a key
+    table: u8,
+    next_key: u64,
+    // whether VOTES_BY_ELECTION lists every vote, unset by canisters that predate the index
+    votes_indexed: Option<bool>,
+    // whether BALLOTS_BY_ELECTION lists every ballot, unset by canisters that predate the index
+    ballots_indexed: Option<bool>,
+}
+
+impl Storable for MigrationState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// a fresh install has no old records to migrate
+pub(crate) fn init_schema() {
+    set_state(MigrationState {
+        code_version: SCHEMA_VERSION,
+        migrated_version: SCHEMA_VERSION,
+        votes_indexed: Some(true),
+        ballots_indexed: Some(true),
+        ..Default::default()
+    });
+    prepare_vote_ids();
+}
+
+// remembers which schema version the outgoing code writes, so the incoming code can refuse a downgrade
+pub(crate) fn save_schema_version() {
+    let mut state = get_state();
+    state.code_version = SCHEMA_VERSION;
+    set_state(state);
+}
+
+// refuses to run over records written by a newer schema, and starts rewriting older records in
+// batches. Until a record is rewritten it is migrated each time it is read.
+pub(crate) fn resume_after_upgrade() {
+    let mut state = get_state();
+    let newest_version = state.code_version.max(state.migrated_version);
+    if newest_version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "cannot downgrade from schema version {} to {}",
+            newest_version, SCHEMA_VERSION
+        ));
+    }
+
+    state.code_version = SCHEMA_VERSION;
+    if state.migrated_version < SCHEMA_VERSION {
+        // records rewritten before an earlier upgrade may carry an older version, so start over
+        state.table = 0;
+        state.next_key = 0;
+        ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+    }
+    if state.votes_indexed != Some(true) {
+        // an upgrade may have cut the previous run short, so start over
+        ic_cdk_timers::set_timer(Duration::ZERO, || index_votes_batch(0));
+    }
+    if state.ballots_indexed != Some(true) {
+        ic_cdk_timers::set_timer(Duration::ZERO, || index_ballots_batch(0));
+    }
+    set_state(state);
+
+    split_id_counter();
+    prepare_vote_ids();
+}
+
+pub(crate) fn votes_indexed() -> bool {
+    get_state().votes_indexed == Some(true)
+}
+
+// adds the next batch of votes from key `from` on to VOTES_BY_ELECTION and schedules the rest.
+// Votes cast or rekeyed meanwhile index themselves.
+fn index_votes_batch(from: u64) {
+    let mut batch: Vec<(u64, Vote)> =
+        VOTES_STORAGE.with(|service| service.borrow().range(from..).take(MIGRATION_BATCH_SIZE + 1).collect());
+    let next_key = if batch.len() > MIGRATION_BATCH_SIZE {
+        batch.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+
+    VOTES_BY_ELECTION.with(|service| {
+        let mut service = service.borrow_mut();
+        for (vote_id, vote) in batch {
+            service.insert((vote.election_id, vote_id), ());
+        }
+    });
+
+    match next_key {
+        Some(key) => {
+            ic_cdk_timers::set_timer(Duration::ZERO, move || index_votes_batch(key));
+        }
+        None => {
+            let mut state = get_state();
+            state.votes_indexed = Some(true);
+            set_state(state);
+        }
+    }
+}
+
+pub(crate) fn ballots_indexed() -> bool {
+    get_state().ballots_indexed == Some(true)
+}
+
+// adds the next batch of ballots from key `from` on to BALLOTS_BY_ELECTION and schedules the rest.
+// Ballots created meanwhile index themselves.
+fn index_ballots_batch(from: u64) {
+    let mut batch: Vec<(u64, u64)> = BALLOTS.with(|service| {
+        service
+            .borrow()
+            .range(from..)
+            .take(MIGRATION_BATCH_SIZE + 1)
+            .map(|(ballot_id, ballot)| (ballot_id, ballot.election_id))
+            .collect()
+    });
+    let next_key = if batch.len() > MIGRATION_BATCH_SIZE {
+        batch.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+
+    BALLOTS_BY_ELECTION.with(|service| {
+        let mut service = service.borrow_mut();
+        for (ballot_id, election_id) in batch {
+            service.insert((election_id, ballot_id), ());
+        }
+    });
+
+    match next_key {
+        Some(key) => {
+            ic_cdk_timers::set_timer(Duration::ZERO, move || index_ballots_batch(key));
+        }
+        None => {
+            let mut state = get_state();
+            state.ballots_indexed = Some(true);
+            set_state(state);
+        }
+    }
+}
+
+// gives every entity its own id sequence, continuing after the highest id the entity got from
+// the shared counter so existing ids stay valid. Votes are renumbered by rekey_votes_batch.
+fn split_id_counter() {
+    if ID_COUNTER.with(|counter| *counter.borrow().get()) == 0 {
+        return;
+    }
+
+    let next_ids = [
+        (
+            Sequence::Election,
+            ELECTIONS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+        ),
+        (
+            Sequence::Voter,
+            VOTERS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+        ),
+        (
+            Sequence::Ballot,
+            BALLOTS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+        ),
+    ];
+    ID_SEQUENCES.with(|service| {
+        let mut service = service.borrow_mut();
+        for (sequence, last_id) in next_ids {
+            service.insert(sequence as u8, last_id.map_or(0, |id| id + 1));
+        }
+    });
+
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(0))
+        .expect("cannot reset id counter");
+}
+
+// seeds the vote id key if it is missing, then moves votes still stored under sequential ids.
+// raw_rand cannot be awaited in init or post_upgrade, so both happen in later messages.
+fn prepare_vote_ids() {
+    if VOTE_ID_KEY.with(|cell| *cell.borrow().get()) == 0 {
+        ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(seed_vote_id_key()));
+    } else if has_sequential_vote_ids() {
+        ic_cdk_timers::set_timer(Duration::ZERO, rekey_votes_batch);
+    }
+}
+
+async fn seed_vote_id_key() {
+    match raw_rand().await {
+        Ok((bytes,)) => {
+            let mut key = [0; 8];
+            key.copy_from_slice(&bytes[..8]);
+            // 0 marks a missing key
+            let key = u64::from_le_bytes(key).max(1);
+            VOTE_ID_KEY
+                .with(|cell| cell.borrow_mut().set(key))
+                .expect("cannot save the vote id key");
+            if has_sequential_vote_ids() {
+                rekey_votes_batch();
+            }
+        }
+        Err((code, msg)) => {
+            ic_cdk::println!("cannot seed the vote id key: {:?} {}, retrying", code, msg);
+            ic_cdk_timers::set_timer(Duration::from_secs(1), || ic_cdk::spawn(seed_vote_id_key()));
+        }
+    }
+}
+
+// votes cast before ids were scrambled sit below VOTE_ID_BIT
+pub(crate) fn has_sequential_vote_ids() -> bool {
+    VOTES_STORAGE.with(|service| service.borrow().range(..VOTE_ID_BIT).next().is_some())
+}
+
+// gives the next batch of sequentially numbered votes an opaque id and schedules the rest
+fn rekey_votes_batch() {
+    let batch: Vec<(u64, Vote)> = VOTES_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range(..VOTE_ID_BIT)
+            .take(MIGRATION_BATCH_SIZE)
+            .collect()
+    });
+
+    for (_, vote) in batch {
+        do_remove_vote(&vote);
+        do_insert_vote(&Vote {
+            id: next_vote_id().expect("votes are rekeyed once the vote id key is seeded"),
+            ..vote
+        });
+    }
+
+    if has_sequential_vote_ids() {
+        ic_cdk_timers::set_timer(Duration::ZERO, rekey_votes_batch);
+    }
+}
+
+// rewrites the next batch of records with the current schema version and schedules the rest
+fn run_migration_batch() {
+    let mut state = get_state();
+    let mut budget = MIGRATION_BATCH_SIZE;
+
+    while budget > 0 {
+        let (rewritten, next_key) = match state.table {
+            0 => VOTES_STORAGE.with(|service| rewrite_records(&mut service.borrow_mut(), state.next_key, budget)),
+            1 => ELECTIONS.with(|service| rewrite_records(&mut service.borrow_mut(), state.next_key, budget)),
+            2 => VOTERS.with(|service| rewrite_records(&mut service.borrow_mut(), state.next_key, budget)),
+            _ => {
+                state.migrated_version = SCHEMA_VERSION;
+                break;
+            }
+        };
+        budget -= rewritten;
+        match next_key {
+            Some(key) => state.next_key = key,
+            None => {
+                state.table += 1;
+                state.next_key = 0;
+            }
+        }
+    }
+
+    let finished = state.migrated_version == SCHEMA_VERSION;
+    set_state(state);
+    if !finished {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+    }
+}
+
+// reads and writes back up to `limit` records from key `from` on, returning how many were
+// rewritten and the key to resume at, or None once the map is done
+fn rewrite_records<V: Storable>(
+    map: &mut StableBTreeMap<u64, V, Memory>,
+    from: u64,
+    limit: usize,
+) -> (usize, Option<u64>) {
+    let mut batch: Vec<(u64, V)> = map.range(from..).take(limit + 1).collect();
+    let next_key = if batch.len() > limit {
+        batch.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+    let rewritten = batch.len();
+    for (key, record) in batch {
+        map.insert(key, record);
+    }
+    (rewritten, next_key)
+}
+
+fn get_state() -> MigrationState {
+    MIGRATION.with(|cell| cell.borrow().get().clone())
+}
+
+fn set_state(state: MigrationState) {
+    MIGRATION
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("cannot save the migration state");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::{Election, ElectionStatus, VotingMethod};
+    use crate::user_management::Voter;
+    use crate::voting::{Selection, Vote};
+    use candid::Principal;
+
+    fn sample_vote() -> Vote {
+        Vote {
+            id: 7,
+            voter_id: 3,
+            candidate: "Ada".to_string(),
+            election_id: 1,
+            timestamp: 1_700_000_000_000_000_000,
+            write_in: false,
+            selection: Selection::Ranking(vec!["Ada".to_string(), "Grace".to_string()]),
+            weight: 2,
+        }
+    }
+
+    fn sample_election() -> Election {
+        Election {
+            id: 1,
+            title: "Board".to_string(),
+            candidates: vec!["Ada".to_string(), "Grace".to_string(), "Alan".to_string()],
+            start_time: 1_700_000_000_000_000_000,
+            end_time: 1_700_086_400_000_000_000,
+            allow_write_ins: false,
+            voting_method: VotingMethod::RankedChoice,
+            seats: 1,
+            max_score: 0,
+            voice_credits: 0,
+            topic: Some("governance".to_string()),
+            tie_break: None,
+            status: ElectionStatus::VotingOpen,
+            tally: None,
+        }
+    }
+
+    fn sample_voter() -> Voter {
+        Voter {
+            id: 3,
+            principal: Principal::from_slice(&[1, 2, 3, 4]),
+            username: "ada".to_string(),
+            registered_elections: vec![1],
+        }
+    }
+
+    fn assert_same_record<T: Versioned>(decoded: T, expected: T) {
+        assert_eq!(encode_record(&decoded), encode_record(&expected));
+    }
+
+    #[test]
+    fn decodes_current_schema_fixtures() {
+        let vote = Vote::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/vote_v1.bin")));
+        assert_same_record(vote, sample_vote());
+
+        let election = Election::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/election_v1.bin")));
+        assert_same_record(election, sample_election());
+
+        let voter = Voter::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/voter_v1.bin")));
+        assert_same_record(voter, sample_voter());
+    }
+
+    // records written before versioning hold the fields of the original schema only
+    #[test]
+    fn migrates_unversioned_fixtures() {
+        let vote = Vote::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/vote_v0.bin")));
+        assert_same_record(
+            vote,
+            Vote {
+                candidate: "Ada".to_string(),
+                selection: Selection::Candidate("Ada".to_string()),
+                weight: 1,
+                ..sample_vote()
+            },
+        );
+
+        let election = Election::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/election_v0.bin")));
+        assert_same_record(
+            election,
+            Election {
+                voting_method: VotingMethod::Plurality,
+                topic: None,
+                status: ElectionStatus::RegistrationOpen,
+                ..sample_election()
+            },
+        );
+
+        let voter = Voter::from_bytes(Cow::Borrowed(include_bytes!("../fixtures/voter_v0.bin")));
+        assert_same_record(
+            voter,
+            Voter {
+                principal: Principal::anonymous(),
+                ..sample_voter()
+            },
+        );
+    }
+
+    // regenerates the fixtures of the current schema version, run after bumping SCHEMA_VERSION
+    // with `cargo test -- --ignored` and keep the fixtures of older versions
+    #[test]
+    #[ignore]
+    fn write_current_schema_fixtures() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, bytes: Vec<u8>| {
+            std::fs::write(dir.join(format!("{}_v{}.bin", name, SCHEMA_VERSION)), bytes).unwrap();
+        };
+        write("vote", encode_record(&sample_vote()));
+        write("election", encode_record(&sample_election()));
+        write("voter", encode_record(&sample_voter()));
+    }
+}
@@ -1,45 +1,1209 @@
+use candid::{Decode, Encode, Principal};
 use ic_cdk::api::time;
-use ic_stable_structures::memory_manager::VirtualMemory;
-use ic_stable_structures::{StableBTreeMap, Storable, DefaultMemoryImpl, MemoryId};
-use std::collections::HashMap;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+use crate::ballot_creation::_get_ballots_for_election;
+use crate::delegation::{delegated_rankings, resolve_delegations, DelegatedWeight};
+use crate::election::{_get_election, is_election_ended, Election, TieBreak, VotingMethod};
+use crate::storage::{OUTCOMES, RUNNING_TALLIES};
+use crate::tabulation::{
+    tabulate_instant_runoff, tabulate_schulze, tabulate_single_transferable_vote, RankedChoiceResult, SchulzeResult,
+    StvResult, TabulationState,
+};
+use crate::user_management::can_observe_election;
+use crate::voting::{_get_election_votes, _get_voter_vote, Selection, Vote};
+use crate::Error;
 
-thread_local! {
-    static VOTES_STORAGE: RefCell<StableBTreeMap<u64, Vote, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// what a running tally counts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TallyEntry {
+    // the voters of the election
+    Turnout,
+    Candidate(String),
+    WriteIn(String),
+    // an option of a referendum ballot
+    Answer { ballot_id: u64, option: String },
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Vote {
-    id: u64,
-    voter_id: u64,
-    candidate: String,
-    election_id: u64,
-    timestamp: u64,
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct TallyKey {
+    pub(crate) election_id: u64,
+    pub(crate) entry: TallyEntry,
+}
+
+impl Storable for TallyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// the direct votes counted for an entry so far, delegated weight is added when results are read
+#[derive(candid::CandidType, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub(crate) struct RunningTally {
+    // weighted votes, or points, for a candidate; the weighted turnout for Turnout
+    pub(crate) weight: u64,
+    // the same counting every voter with a weight of 1; the number of voters for Turnout
+    pub(crate) headcount: u64,
+    // voice credits spent on a candidate in a quadratic election
+    pub(crate) credits_spent: u64,
+}
+
+impl RunningTally {
+    fn add(&mut self, other: &RunningTally) {
+        self.weight += other.weight;
+        self.headcount += other.headcount;
+        self.credits_spent += other.credits_spent;
+    }
+}
+
+impl Storable for RunningTally {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// an entry whose running tally differed from a recount of the stored votes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TallyMismatch {
+    pub(crate) entry: TallyEntry,
+    pub(crate) running: Option<RunningTally>,
+    pub(crate) recounted: Option<RunningTally>,
+}
+
+// one candidate's line in an election result
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CandidateResult {
+    pub(crate) candidate: String,
+    // a write-in rather than one of Election.candidates
+    pub(crate) write_in: bool,
+    // weighted votes, including delegated weight; first choices for ranked elections, approvals for
+    // approval elections, points for Borda elections and total scores for score elections
+    pub(crate) votes: u64,
+    // the same votes counting every voter, direct or delegating, with a weight of 1
+    pub(crate) headcount: u64,
+    // the part of the votes that arrived through delegation
+    pub(crate) delegated: u64,
+    // share of the weighted turnout that voted for, or approved, the candidate; not set for Borda,
+    // score and quadratic elections
+    pub(crate) share: Option<f64>,
+    // mean weighted points or score per unit of weight; only set for Borda and score elections
+    pub(crate) mean: Option<f64>,
+    // voice credits spent on the candidate; only set for quadratic elections
+    pub(crate) credits_spent: Option<u64>,
+}
+
+// candidates tied for the last places, which their votes alone cannot fill, or tied in a round of
+// a tabulation for the candidate it elects or eliminates
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Tie {
+    // in Election.candidates order
+    pub(crate) candidates: Vec<String>,
+    // the number of places left to fill among them, or of candidates to elect or eliminate
+    pub(crate) places: u32,
+    // the votes each tied candidate holds, in millionths of a vote for STV rounds
+    pub(crate) votes: u64,
+}
+
+// where a tie was met
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum TieStage {
+    // the tie for the last places among the winners
+    Winners,
+    // the weakest candidates of an instant runoff or STV round
+    Elimination { round: u32 },
+    // the strongest candidates at or above quota in an STV round
+    Election { round: u32 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ElectionResult {
+    pub(crate) election_id: u64,
+    // every candidate of the election and every write-in that received votes, most votes first.
+    // Candidates with equal votes keep their Election.candidates order, followed by write-ins by name.
+    pub(crate) candidates: Vec<CandidateResult>,
+    // number of voters counted, including write-ins and delegators
+    pub(crate) turnout: u64,
+    // sum of the weights of the voters counted
+    pub(crate) weighted_turnout: u64,
+    // number of voters counted through delegation
+    pub(crate) delegated_turnout: u64,
+    // the instant runoff winner for ranked choice elections, the elected candidates in order of
+    // election for STV elections, and otherwise the candidates with the most votes, one per seat
+    pub(crate) winners: Vec<String>,
+    // set when a tie leaves places unfilled; the tied candidates are not among the winners
+    pub(crate) tie: Option<Tie>,
+    // how the election's tie-break policy decided a tie, whose chosen candidates joined the winners
+    pub(crate) tie_break: Option<TieBreakDecision>,
+}
+
+// what a tie-break decision was based on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TieBreakBasis {
+    // when each tied candidate received their last vote
+    EarliestVote { last_votes: Vec<(String, u64)> },
+    LotDraw { recorded_by: Principal },
+    // the raw_rand bytes the candidates were drawn with, each draw taking the next 8 bytes
+    Random { randomness: Vec<u8> },
+    Runoff,
+    // the candidate listed first is elected, or the one listed last eliminated, for ties in the
+    // rounds of a tabulation the election's policy cannot take to a runoff or has no policy for
+    ListingOrder,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TieBreakDecision {
+    pub(crate) stage: TieStage,
+    pub(crate) tie: Tie,
+    pub(crate) basis: TieBreakBasis,
+    // the tied candidates given the places, or eliminated; none when they go to a runoff
+    pub(crate) chosen: Vec<String>,
+}
+
+// breaks ties by an election's tie-break policy, taking the draws already made for its tally
+#[derive(Default)]
+pub(crate) struct TieBreaker<'a> {
+    pub(crate) policy: Option<TieBreak>,
+    // when each candidate last received a vote, for EarliestVote
+    pub(crate) last_votes: &'a [(String, u64)],
+    // the lot draws and raw_rand draws made so far
+    pub(crate) draws: &'a [TieBreakDecision],
+}
+
+impl TieBreaker<'_> {
+    // decides a tie, or returns None while it waits for a draw and for a tie among the winners of
+    // an election without a policy, which is left open
+    pub(crate) fn decide(&self, stage: TieStage, tie: &Tie) -> Option<TieBreakDecision> {
+        if let Some(draw) = self.draws.iter().find(|draw| draw.stage == stage && draw.tie == *tie) {
+            return Some(draw.clone());
+        }
+
+        let places = tie.places as usize;
+        let eliminating = matches!(stage, TieStage::Elimination { .. });
+        let (basis, chosen) = match (self.policy, stage) {
+            (Some(TieBreak::EarliestVote), _) => {
+                let everyone = Tie {
+                    places: tie.candidates.len() as u32,
+                    ..tie.clone()
+                };
+                let (order, last_votes) = earliest_voted(&everyone, self.last_votes);
+                // the candidates who reached the tied total last are eliminated first
+                let chosen = if eliminating {
+                    order.into_iter().rev().take(places).collect()
+                } else {
+                    order.into_iter().take(places).collect()
+                };
+                (TieBreakBasis::EarliestVote { last_votes }, chosen)
+            }
+            (Some(TieBreak::Random | TieBreak::LotDraw), _) | (None, TieStage::Winners) => return None,
+            (Some(TieBreak::Runoff), TieStage::Winners) => (TieBreakBasis::Runoff, Vec::new()),
+            _ if eliminating => (
+                TieBreakBasis::ListingOrder,
+                tie.candidates.iter().rev().take(places).cloned().collect(),
+            ),
+            _ => (
+                TieBreakBasis::ListingOrder,
+                tie.candidates.iter().take(places).cloned().collect(),
+            ),
+        };
+        Some(TieBreakDecision {
+            stage,
+            tie: tie.clone(),
+            basis,
+            chosen,
+        })
+    }
+}
+
+// the results of a finished tally job, stored in OUTCOMES
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TallyOutcome {
+    pub(crate) result: ElectionResult,
+    // set for ranked choice elections
+    pub(crate) ranked_choice: Option<RankedChoiceResult>,
+    // set for single transferable vote elections
+    pub(crate) stv: Option<StvResult>,
+    // set for every election with ranked ballots
+    pub(crate) schulze: Option<SchulzeResult>,
+    // the answers to each ballot of a referendum
+    pub(crate) ballots: Vec<BallotResult>,
+    pub(crate) votes_counted: u64,
+    pub(crate) tallied_at: u64,
+}
+
+impl TallyOutcome {
+    pub(crate) fn summary(&self) -> TallySummary {
+        TallySummary {
+            winners: self.result.winners.clone(),
+            tie: self.result.tie.clone(),
+            votes_counted: self.votes_counted,
+            tallied_at: self.tallied_at,
+        }
+    }
+}
+
+impl Storable for TallyOutcome {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// the part of a tally outcome kept on its election
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TallySummary {
+    pub(crate) winners: Vec<String>,
+    // set when a tie leaves places unfilled
+    pub(crate) tie: Option<Tie>,
+    pub(crate) votes_counted: u64,
+    pub(crate) tallied_at: u64,
+}
+
+// a helper method to get the outcome stored by an election's tally job
+pub(crate) fn _get_outcome(election_id: u64) -> Option<TallyOutcome> {
+    OUTCOMES.with(|service| service.borrow().get(&election_id))
+}
+
+// Helper method to perform insert for outcomes, summarising the outcome on its election
+pub(crate) fn do_insert_outcome(election: &mut Election, outcome: TallyOutcome) {
+    election.tally = Some(outcome.summary());
+    OUTCOMES.with(|service| service.borrow_mut().insert(election.id, outcome));
+}
+
+#[ic_cdk::query]
+fn get_election_results(election_id: u64) -> Result<ElectionResult, Error> {
+    let election = caller_viewable_results(election_id)?;
+    if let Some(outcome) = _get_outcome(election_id) {
+        return Ok(outcome.result);
+    }
+
+    let counts = election_counts(&election);
+    // only the tabulation rounds of a preview break ties, the tie among its winners is left open
+    let last_votes = match election.voting_method {
+        VotingMethod::RankedChoice | VotingMethod::SingleTransferableVote => last_votes(&election),
+        _ => Vec::new(),
+    };
+    let breaker = TieBreaker {
+        policy: election.tie_break,
+        last_votes: &last_votes,
+        draws: &[],
+    };
+    let (ranked_choice, stv) = match election.voting_method {
+        VotingMethod::RankedChoice => (
+            Some(tabulate_instant_runoff(
+                &election.candidates,
+                &ranked_ballots(&election),
+                &breaker,
+            )),
+            None,
+        ),
+        VotingMethod::SingleTransferableVote => (
+            None,
+            Some(tabulate_single_transferable_vote(
+                &election.candidates,
+                election.seats,
+                &ranked_ballots(&election),
+                &breaker,
+            )),
+        ),
+        _ => (None, None),
+    };
+    Ok(election_result(
+        &election,
+        &counts,
+        &delegated_counts(&election, &resolve_delegations(&election)),
+        ranked_choice.as_ref(),
+        stv.as_ref(),
+    ))
+}
+
+// the result of an election from its direct counts, adding what the weight delegated to its
+// voters counts for. Ranked choice and STV elections take their winners from their tabulation.
+fn election_result(
+    election: &Election,
+    counts: &BTreeMap<TallyEntry, RunningTally>,
+    arrived_counts: &BTreeMap<TallyEntry, RunningTally>,
+    ranked_choice: Option<&RankedChoiceResult>,
+    stv: Option<&StvResult>,
+) -> ElectionResult {
+    let direct = counts.get(&TallyEntry::Turnout).copied().unwrap_or_default();
+    let arrived = arrived_counts.get(&TallyEntry::Turnout).copied().unwrap_or_default();
+    let delegated_turnout = arrived.headcount;
+    let turnout = direct.headcount + delegated_turnout;
+    let weighted_turnout = direct.weight + arrived.weight;
+
+    // listed candidates first, in order, so the stable sort below keeps that order among equal votes
+    let entries = election
+        .candidates
+        .iter()
+        .map(|candidate| (TallyEntry::Candidate(candidate.clone()), candidate, false))
+        .chain(counts.keys().filter_map(|entry| match entry {
+            TallyEntry::WriteIn(candidate) => Some((entry.clone(), candidate, true)),
+            _ => None,
+        }));
+    let mut candidates: Vec<CandidateResult> = entries
+        .map(|(entry, candidate, write_in)| {
+            let direct = counts.get(&entry).copied().unwrap_or_default();
+            let arrived = arrived_counts.get(&entry).copied().unwrap_or_default();
+            let votes = direct.weight + arrived.weight;
+            let per_ballot = if weighted_turnout == 0 {
+                0.0
+            } else {
+                votes as f64 / weighted_turnout as f64
+            };
+            CandidateResult {
+                candidate: candidate.clone(),
+                write_in,
+                votes,
+                headcount: direct.headcount + arrived.headcount,
+                delegated: arrived.weight,
+                share: match election.voting_method {
+                    VotingMethod::Borda | VotingMethod::Score | VotingMethod::Quadratic => None,
+                    _ => Some(per_ballot),
+                },
+                mean: matches!(election.voting_method, VotingMethod::Borda | VotingMethod::Score).then_some(per_ballot),
+                credits_spent: (election.voting_method == VotingMethod::Quadratic).then_some(direct.credits_spent),
+            }
+        })
+        .collect();
+    candidates.sort_by_key(|result| std::cmp::Reverse(result.votes));
+
+    let (winners, tie) = match (ranked_choice, stv) {
+        (Some(ranked_choice), _) => instant_runoff_winners(ranked_choice),
+        (_, Some(stv)) => (stv.elected.clone(), None),
+        _ => most_votes(&candidates, election.seats.max(1) as usize),
+    };
+
+    ElectionResult {
+        election_id: election.id,
+        candidates,
+        turnout,
+        weighted_turnout,
+        delegated_turnout,
+        winners,
+        tie,
+        tie_break: None,
+    }
+}
+
+// the candidates with the most votes, one per seat, and the tie for the last seats if there is one.
+// Candidates without votes are never elected.
+fn most_votes(candidates: &[CandidateResult], seats: usize) -> (Vec<String>, Option<Tie>) {
+    let voted: Vec<&CandidateResult> = candidates.iter().filter(|result| result.votes > 0).collect();
+    if voted.len() <= seats || voted[seats - 1].votes > voted[seats].votes {
+        let winners = voted
+            .iter()
+            .take(seats)
+            .map(|result| result.candidate.clone())
+            .collect();
+        return (winners, None);
+    }
+
+    let cutoff = voted[seats - 1].votes;
+    let winners: Vec<String> = voted
+        .iter()
+        .take_while(|result| result.votes > cutoff)
+        .map(|result| result.candidate.clone())
+        .collect();
+    let tie = Tie {
+        candidates: voted
+            .iter()
+            .filter(|result| result.votes == cutoff)
+            .map(|result| result.candidate.clone())
+            .collect(),
+        places: (seats - winners.len()) as u32,
+        votes: cutoff,
+    };
+    (winners, Some(tie))
+}
+
+// the instant runoff winner, or the tie among the candidates left in its last round. A tabulation
+// waiting for a draw in an earlier round has neither.
+fn instant_runoff_winners(result: &RankedChoiceResult) -> (Vec<String>, Option<Tie>) {
+    if let Some(winner) = &result.winner {
+        return (vec![winner.clone()], None);
+    }
+    if result.awaiting_draw.is_some() {
+        return (Vec::new(), None);
+    }
+
+    let tie = result.rounds.last().and_then(|round| {
+        let votes = round.tallies.first().map_or(0, |(_, votes)| *votes);
+        (round.tallies.len() > 1 && votes > 0).then(|| Tie {
+            candidates: round.tallies.iter().map(|(candidate, _)| candidate.clone()).collect(),
+            places: 1,
+            votes,
+        })
+    });
+    (Vec::new(), tie)
+}
+
+// gives the tied places of a result to the candidates a decision chose, recording the decision
+pub(crate) fn break_tie(result: &mut ElectionResult, decision: TieBreakDecision) {
+    result.tie = None;
+    result.winners.extend(decision.chosen.iter().cloned());
+    result.tie_break = Some(decision);
+}
+
+// the tied candidates whose last votes were cast first, with the time of each one's last vote.
+// Votes cast at the same time leave the candidates in their tied order.
+fn earliest_voted(tie: &Tie, last_votes: &[(String, u64)]) -> (Vec<String>, Vec<(String, u64)>) {
+    let tied: Vec<(String, u64)> = tie
+        .candidates
+        .iter()
+        .map(|candidate| {
+            let last_vote = last_votes
+                .iter()
+                .find(|(voted, _)| voted == candidate)
+                .map_or(0, |(_, timestamp)| *timestamp);
+            (candidate.clone(), last_vote)
+        })
+        .collect();
+    let mut order = tied.clone();
+    order.sort_by_key(|(_, last_vote)| *last_vote);
+    let chosen = order
+        .into_iter()
+        .take(tie.places as usize)
+        .map(|(candidate, _)| candidate)
+        .collect();
+    (chosen, tied)
+}
+
+// draws the tied places one at a time, each draw picking among the candidates left with the
+// next 8 bytes of randomness
+pub(crate) fn drawn_by_lot(tie: &Tie, randomness: &[u8]) -> Vec<String> {
+    let mut remaining = tie.candidates.clone();
+    let mut chosen = Vec::new();
+    for bytes in randomness.chunks_exact(8).cycle().take(tie.places as usize) {
+        let mut draw = [0; 8];
+        draw.copy_from_slice(bytes);
+        let picked = u64::from_le_bytes(draw) % remaining.len() as u64;
+        chosen.push(remaining.remove(picked as usize));
+    }
+    chosen
+}
+
+// the candidates a vote counts towards: those it gives points to, and on a ranked choice or STV
+// ballot every candidate ranked, as its later preferences may transfer to them. The time of a
+// ranked ballot is thus noted against candidates it never reaches in a round, so EarliestVote
+// compares when candidates were last ranked at all, not when each round's ballots reached them.
+fn backed_candidates(election: &Election, vote: &Vote) -> Vec<String> {
+    if let (VotingMethod::RankedChoice | VotingMethod::SingleTransferableVote, Selection::Ranking(ranking)) =
+        (election.voting_method, &vote.selection)
+    {
+        return ranking.clone();
+    }
+    counted_points(election, vote)
+        .into_iter()
+        .filter_map(|(entry, points)| match entry {
+            TallyEntry::Candidate(candidate) | TallyEntry::WriteIn(candidate) if points > 0 => Some(candidate),
+            _ => None,
+        })
+        .collect()
+}
+
+// notes the time of a vote against every candidate it counts towards, for elections that break
+// ties by the earliest vote
+pub(crate) fn add_last_vote(last_votes: &mut BTreeMap<String, u64>, election: &Election, vote: &Vote) {
+    if election.tie_break != Some(TieBreak::EarliestVote) {
+        return;
+    }
+    for candidate in backed_candidates(election, vote) {
+        let last_vote = last_votes.entry(candidate).or_default();
+        *last_vote = (*last_vote).max(vote.timestamp);
+    }
 }
 
+// when each candidate of an election last received a vote, for results read before a tally.
+// Empty unless the election breaks ties by the earliest vote, so other elections skip reading
+// their votes.
+fn last_votes(election: &Election) -> Vec<(String, u64)> {
+    if election.tie_break != Some(TieBreak::EarliestVote) {
+        return Vec::new();
+    }
+    let mut last_votes = BTreeMap::new();
+    for vote in _get_election_votes(election.id) {
+        add_last_vote(&mut last_votes, election, &vote);
+    }
+    last_votes.into_iter().collect()
+}
 
-// Function to calculate election results
-pub fn calculate_election_results(election_id: u64) -> HashMap<String, u64> {
-    let votes = VOTES_STORAGE.with(|service| {
-        service
-            .borrow()
-            .values()
-            .filter(|vote| vote.election_id == election_id)
-            .collect::<Vec<_>>()
+#[ic_cdk::query]
+fn get_write_in_results(election_id: u64) -> Result<Vec<(String, u64)>, Error> {
+    Ok(get_election_results(election_id)?
+        .candidates
+        .into_iter()
+        .filter(|result| result.write_in)
+        .map(|result| (result.candidate, result.votes))
+        .collect())
+}
+
+// the results of an election from its direct and delegated counts and the tabulations of its
+// ranked ballots
+pub(crate) fn election_outcome(
+    election: &Election,
+    counts: &BTreeMap<TallyEntry, RunningTally>,
+    arrived_counts: &BTreeMap<TallyEntry, RunningTally>,
+    tabulated: TabulationState,
+) -> TallyOutcome {
+    TallyOutcome {
+        result: election_result(
+            election,
+            counts,
+            arrived_counts,
+            tabulated.ranked_choice.as_ref(),
+            tabulated.stv.as_ref(),
+        ),
+        ranked_choice: tabulated.ranked_choice,
+        stv: tabulated.stv,
+        schulze: tabulated.schulze,
+        ballots: ballot_results(election, counts, arrived_counts),
+        votes_counted: counts.get(&TallyEntry::Turnout).map_or(0, |turnout| turnout.headcount),
+        tallied_at: time(),
+    }
+}
+
+pub(crate) fn has_ranked_ballots(election: &Election) -> bool {
+    matches!(
+        election.voting_method,
+        VotingMethod::RankedChoice | VotingMethod::SingleTransferableVote | VotingMethod::Borda
+    )
+}
+
+// replaces the running tallies of an election with a recount of its votes, returning where they
+// differed. Elections created before running tallies were kept have none until their first
+// recount, and are recounted on every read until then.
+pub(crate) fn replace_running_tallies(
+    election_id: u64,
+    recounted: BTreeMap<TallyEntry, RunningTally>,
+) -> Vec<TallyMismatch> {
+    let running = _get_running_tallies(election_id).unwrap_or_default();
+
+    let mut entries: Vec<&TallyEntry> = recounted.keys().chain(running.keys()).collect();
+    entries.sort();
+    entries.dedup();
+    let mismatches = entries
+        .into_iter()
+        .filter(|entry| running.get(*entry) != recounted.get(*entry))
+        .map(|entry| TallyMismatch {
+            entry: entry.clone(),
+            running: running.get(entry).copied(),
+            recounted: recounted.get(entry).copied(),
+        })
+        .collect();
+
+    RUNNING_TALLIES.with(|service| {
+        let mut service = service.borrow_mut();
+        for entry in running.into_keys() {
+            service.remove(&TallyKey { election_id, entry });
+        }
+        for (entry, tally) in recounted {
+            service.insert(TallyKey { election_id, entry }, tally);
+        }
     });
+    mismatches
+}
+
+#[ic_cdk::query]
+fn get_ranked_choice_results(election_id: u64) -> Result<RankedChoiceResult, Error> {
+    let election = caller_viewable_results(election_id)?;
+
+    if election.voting_method != VotingMethod::RankedChoice {
+        return Err(Error::InvalidPayload {
+            msg: format!("election with id={} is not a ranked choice election", election_id),
+        });
+    }
+
+    if let Some(result) = _get_outcome(election_id).and_then(|outcome| outcome.ranked_choice) {
+        return Ok(result);
+    }
 
-    let mut result_map = HashMap::new();
+    let ballots = ranked_ballots(&election);
+    let last_votes = last_votes(&election);
+    let breaker = TieBreaker {
+        policy: election.tie_break,
+        last_votes: &last_votes,
+        draws: &[],
+    };
+    Ok(tabulate_instant_runoff(&election.candidates, &ballots, &breaker))
+}
+
+#[ic_cdk::query]
+fn get_stv_results(election_id: u64) -> Result<StvResult, Error> {
+    let election = caller_viewable_results(election_id)?;
+
+    if election.voting_method != VotingMethod::SingleTransferableVote {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "election with id={} is not a single transferable vote election",
+                election_id
+            ),
+        });
+    }
+
+    if let Some(result) = _get_outcome(election_id).and_then(|outcome| outcome.stv) {
+        return Ok(result);
+    }
+
+    let ballots = ranked_ballots(&election);
+    let last_votes = last_votes(&election);
+    let breaker = TieBreaker {
+        policy: election.tie_break,
+        last_votes: &last_votes,
+        draws: &[],
+    };
+    Ok(tabulate_single_transferable_vote(
+        &election.candidates,
+        election.seats,
+        &ballots,
+        &breaker,
+    ))
+}
+
+#[ic_cdk::query]
+fn get_schulze_results(election_id: u64) -> Result<SchulzeResult, Error> {
+    let election = caller_viewable_results(election_id)?;
+
+    if !has_ranked_ballots(&election) {
+        return Err(Error::InvalidPayload {
+            msg: format!("election with id={} does not use ranked ballots", election_id),
+        });
+    }
+
+    if let Some(result) = _get_outcome(election_id).and_then(|outcome| outcome.schulze) {
+        return Ok(result);
+    }
+
+    let ballots = ranked_ballots(&election);
+    Ok(tabulate_schulze(&election.candidates, &ballots))
+}
+
+// the distinct rankings cast in an election, each with the summed weight of the votes that cast it
+// and of the votes delegated to them
+fn ranked_ballots(election: &Election) -> Vec<(Vec<String>, u64)> {
+    let mut ballots = BTreeMap::new();
+    for vote in _get_election_votes(election.id) {
+        if let Selection::Ranking(ranking) = vote.selection {
+            *ballots.entry(ranking).or_insert(0) += vote.weight;
+        }
+    }
+    for (ranking, weight) in delegated_rankings(election) {
+        *ballots.entry(ranking).or_insert(0) += weight;
+    }
+    ballots.into_iter().collect()
+}
+
+// loads an election whose results are public, or visible to the caller as an observer
+pub(crate) fn caller_viewable_results(election_id: u64) -> Result<Election, Error> {
+    let election = _get_election(&election_id).ok_or(Error::NotFound {
+        msg: format!("an election with id={} not found", election_id),
+    })?;
+
+    if is_election_ended(&election) || can_observe_election(election_id) {
+        Ok(election)
+    } else {
+        // Election is still ongoing
+        Err(Error::ElectionOngoing {
+            msg: "cannot retrieve results until the election ends".to_string(),
+        })
+    }
+}
+
+// the points a vote gives each entry it counts toward: first choices for ranked elections,
+// approvals for approval elections, points for Borda elections, scores for score elections and
+// the chosen options for referendums
+fn counted_points(election: &Election, vote: &Vote) -> Vec<(TallyEntry, u64)> {
+    if let Selection::Answers(answers) = &vote.selection {
+        return answers
+            .iter()
+            .map(|(ballot_id, option)| {
+                let entry = TallyEntry::Answer {
+                    ballot_id: *ballot_id,
+                    option: option.clone(),
+                };
+                (entry, 1)
+            })
+            .collect();
+    }
+    let counted: Vec<(String, u64)> = match &vote.selection {
+        Selection::Approvals(approvals) => approvals.iter().map(|candidate| (candidate.clone(), 1)).collect(),
+        Selection::Scores(scores) | Selection::Allocations(scores) => scores
+            .iter()
+            .map(|(candidate, score)| (candidate.clone(), *score as u64))
+            .collect(),
+        // n-1 points for a first preference down to 0 for unranked candidates
+        Selection::Ranking(ranking) if election.voting_method == VotingMethod::Borda => {
+            let top_points = election.candidates.len() as u64 - 1;
+            ranking
+                .iter()
+                .enumerate()
+                .map(|(position, candidate)| (candidate.clone(), top_points - position as u64))
+                .collect()
+        }
+        _ => vec![(vote.candidate.clone(), 1)],
+    };
+    counted
+        .into_iter()
+        .map(|(candidate, points)| match vote.write_in {
+            true => (TallyEntry::WriteIn(candidate), points),
+            false => (TallyEntry::Candidate(candidate), points),
+        })
+        .collect()
+}
+
+// adds a vote to the tallies counted so far
+pub(crate) fn add_vote(counts: &mut BTreeMap<TallyEntry, RunningTally>, election: &Election, vote: &Vote) {
+    counts.entry(TallyEntry::Turnout).or_default().add(&RunningTally {
+        weight: vote.weight,
+        headcount: 1,
+        credits_spent: 0,
+    });
+    let quadratic = matches!(vote.selection, Selection::Allocations(_));
+    for (entry, points) in counted_points(election, vote) {
+        counts.entry(entry).or_default().add(&RunningTally {
+            weight: points * vote.weight,
+            headcount: points,
+            credits_spent: if quadratic { points * points } else { 0 },
+        });
+    }
+}
 
+// tallies votes from scratch; the Turnout entry is always present
+fn count_votes(election: &Election, votes: impl IntoIterator<Item = Vote>) -> BTreeMap<TallyEntry, RunningTally> {
+    let mut counts = BTreeMap::from([(TallyEntry::Turnout, RunningTally::default())]);
     for vote in votes {
-        let candidate_count = result_map.entry(vote.candidate.clone()).or_insert(0);
-        *candidate_count += 1;
+        add_vote(&mut counts, election, &vote);
+    }
+    counts
+}
+
+// what the weight delegated to the voters who voted adds to the entries their votes count toward,
+// and to the Turnout entry
+fn delegated_counts(
+    election: &Election,
+    delegations: &HashMap<u64, DelegatedWeight>,
+) -> BTreeMap<TallyEntry, RunningTally> {
+    let mut counts: BTreeMap<TallyEntry, RunningTally> = BTreeMap::new();
+    for (voter_id, arrived) in delegations {
+        if let Some(vote) = _get_voter_vote(election.id, *voter_id) {
+            add_delegated_vote(&mut counts, election, &vote, arrived);
+        }
+    }
+    counts
+}
+
+// adds the weight delegated to a voter to the delegated counts of what their vote counts toward
+pub(crate) fn add_delegated_vote(
+    counts: &mut BTreeMap<TallyEntry, RunningTally>,
+    election: &Election,
+    vote: &Vote,
+    arrived: &DelegatedWeight,
+) {
+    counts.entry(TallyEntry::Turnout).or_default().add(&RunningTally {
+        weight: arrived.weight,
+        headcount: arrived.voters,
+        credits_spent: 0,
+    });
+    for (entry, points) in counted_points(election, vote) {
+        counts.entry(entry).or_default().add(&RunningTally {
+            weight: points * arrived.weight,
+            headcount: points * arrived.voters,
+            credits_spent: 0,
+        });
+    }
+}
+
+// the running tallies of an election, or a recount if it has none yet
+fn election_counts(election: &Election) -> BTreeMap<TallyEntry, RunningTally> {
+    _get_running_tallies(election.id).unwrap_or_else(|| count_votes(election, _get_election_votes(election.id)))
+}
+
+// a helper method to get the running tallies of an election, None if it does not keep them
+pub(crate) fn _get_running_tallies(election_id: u64) -> Option<BTreeMap<TallyEntry, RunningTally>> {
+    let first = TallyKey {
+        election_id,
+        entry: TallyEntry::Turnout,
+    };
+    RUNNING_TALLIES.with(|service| {
+        let service = service.borrow();
+        service.get(&first)?;
+        Some(
+            service
+                .range(first..)
+                .take_while(|(key, _)| key.election_id == election_id)
+                .map(|(key, tally)| (key.entry, tally))
+                .collect(),
+        )
+    })
+}
+
+// starts the running tallies of a new election at zero
+pub(crate) fn init_running_tallies(election_id: u64) {
+    RUNNING_TALLIES.with(|service| {
+        service.borrow_mut().insert(
+            TallyKey {
+                election_id,
+                entry: TallyEntry::Turnout,
+            },
+            RunningTally::default(),
+        )
+    });
+}
+
+// adds a newly cast vote to the running tallies of its election, if the election keeps them
+pub(crate) fn record_vote(election: &Election, vote: &Vote) {
+    let mut counts = BTreeMap::new();
+    add_vote(&mut counts, election, vote);
+    RUNNING_TALLIES.with(|service| {
+        let mut service = service.borrow_mut();
+        let turnout = TallyKey {
+            election_id: election.id,
+            entry: TallyEntry::Turnout,
+        };
+        if !service.contains_key(&turnout) {
+            return;
+        }
+        for (entry, added) in counts {
+            let key = TallyKey {
+                election_id: election.id,
+                entry,
+            };
+            let mut tally = service.get(&key).unwrap_or_default();
+            tally.add(&added);
+            service.insert(key, tally);
+        }
+    });
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BallotResult {
+    pub(crate) ballot_id: u64,
+    pub(crate) question: String,
+    // weighted answers per option, including delegated weight
+    pub(crate) tallies: Vec<(String, u64)>,
+}
+
+// Function to tally the answers to each ballot of a referendum
+#[ic_cdk::query]
+fn get_ballot_results(election_id: u64) -> Result<Vec<BallotResult>, Error> {
+    let election = caller_viewable_results(election_id)?;
+    if let Some(outcome) = _get_outcome(election_id) {
+        return Ok(outcome.ballots);
+    }
+
+    let counts = election_counts(&election);
+    let arrived_counts = delegated_counts(&election, &resolve_delegations(&election));
+    Ok(ballot_results(&election, &counts, &arrived_counts))
+}
+
+// the answers to each ballot of an election from its direct and delegated counts
+fn ballot_results(
+    election: &Election,
+    counts: &BTreeMap<TallyEntry, RunningTally>,
+    arrived_counts: &BTreeMap<TallyEntry, RunningTally>,
+) -> Vec<BallotResult> {
+    _get_ballots_for_election(election.id)
+        .into_iter()
+        .map(|ballot| BallotResult {
+            ballot_id: ballot.id,
+            question: ballot.question,
+            tallies: ballot
+                .options
+                .into_iter()
+                .map(|option| {
+                    let entry = TallyEntry::Answer {
+                        ballot_id: ballot.id,
+                        option: option.clone(),
+                    };
+                    let direct = counts.get(&entry).map_or(0, |tally| tally.weight);
+                    let arrived = arrived_counts.get(&entry).map_or(0, |tally| tally.weight);
+                    (option, direct + arrived)
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // candidates in the order results list them, most votes first
+    fn rows(votes: &[(&str, u64)]) -> Vec<CandidateResult> {
+        votes
+            .iter()
+            .map(|(candidate, votes)| CandidateResult {
+                candidate: candidate.to_string(),
+                write_in: false,
+                votes: *votes,
+                headcount: *votes,
+                delegated: 0,
+                share: None,
+                mean: None,
+                credits_spent: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn most_votes_elects_a_clear_leader() {
+        let (winners, tie) = most_votes(&rows(&[("A", 5), ("B", 3), ("C", 1)]), 1);
+
+        assert_eq!(winners, vec!["A".to_string()]);
+        assert!(tie.is_none());
+    }
+
+    #[test]
+    fn most_votes_reports_a_tie_at_the_cutoff_with_the_places_left() {
+        let (winners, tie) = most_votes(&rows(&[("A", 5), ("B", 3), ("C", 3), ("D", 3), ("E", 1)]), 3);
+
+        assert_eq!(winners, vec!["A".to_string()]);
+        let tie = tie.expect("B, C and D tie for the last two seats");
+        assert_eq!(tie.candidates, vec!["B".to_string(), "C".to_string(), "D".to_string()]);
+        assert_eq!(tie.places, 2);
+        assert_eq!(tie.votes, 3);
+    }
+
+    #[test]
+    fn most_votes_leaves_a_tie_below_the_cutoff_alone() {
+        let (winners, tie) = most_votes(&rows(&[("A", 5), ("B", 4), ("C", 2), ("D", 2)]), 2);
+
+        assert_eq!(winners, vec!["A".to_string(), "B".to_string()]);
+        assert!(tie.is_none());
+    }
+
+    #[test]
+    fn most_votes_elects_no_one_without_votes() {
+        let (winners, tie) = most_votes(&rows(&[("A", 0), ("B", 0)]), 1);
+
+        assert!(winners.is_empty());
+        assert!(tie.is_none());
+    }
+
+    #[test]
+    fn most_votes_fills_only_the_seats_with_votes() {
+        let (winners, tie) = most_votes(&rows(&[("A", 2), ("B", 0), ("C", 0)]), 2);
+
+        assert_eq!(winners, vec!["A".to_string()]);
+        assert!(tie.is_none());
+    }
+
+    fn tie(candidates: &[&str], places: u32) -> Tie {
+        Tie {
+            candidates: candidates.iter().map(|candidate| candidate.to_string()).collect(),
+            places,
+            votes: 1,
+        }
+    }
+
+    #[test]
+    fn drawn_by_lot_picks_each_place_with_the_next_8_bytes() {
+        let mut randomness = 1u64.to_le_bytes().to_vec();
+        randomness.extend(0u64.to_le_bytes());
+
+        // 1 % 3 picks B, then 0 % 2 picks A from the candidates left
+        assert_eq!(drawn_by_lot(&tie(&["A", "B", "C"], 2), &randomness), vec!["B", "A"]);
+    }
+
+    #[test]
+    fn drawn_by_lot_reuses_the_randomness_for_more_places_than_it_covers() {
+        let randomness = 2u64.to_le_bytes();
+
+        // 2 % 3 picks C, then 2 % 2 picks A
+        assert_eq!(drawn_by_lot(&tie(&["A", "B", "C"], 2), &randomness), vec!["C", "A"]);
+    }
+
+    #[test]
+    fn earliest_voted_chooses_the_candidates_whose_last_votes_came_first() {
+        let last_votes = [("A".to_string(), 30), ("C".to_string(), 10)];
+        let (chosen, tied) = earliest_voted(&tie(&["A", "B", "C"], 2), &last_votes);
+
+        // B never received a vote, which counts as the earliest
+        assert_eq!(chosen, vec!["B", "C"]);
+        assert_eq!(
+            tied,
+            vec![("A".to_string(), 30), ("B".to_string(), 0), ("C".to_string(), 10)]
+        );
+    }
+
+    #[test]
+    fn add_last_vote_notes_a_ranked_ballot_against_every_candidate_it_ranks() {
+        let election = Election {
+            voting_method: VotingMethod::RankedChoice,
+            tie_break: Some(TieBreak::EarliestVote),
+            candidates: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            ..Election::default()
+        };
+        let ranking = |ranking: &[&str], timestamp: u64| Vote {
+            id: timestamp,
+            voter_id: timestamp,
+            candidate: ranking[0].to_string(),
+            election_id: 0,
+            timestamp,
+            write_in: false,
+            selection: Selection::Ranking(ranking.iter().map(|candidate| candidate.to_string()).collect()),
+            weight: 1,
+        };
+
+        let mut last_votes = BTreeMap::new();
+        add_last_vote(&mut last_votes, &election, &ranking(&["A", "B"], 10));
+        add_last_vote(&mut last_votes, &election, &ranking(&["C", "A"], 20));
+        // the later ballot counts for A, though its first preference C may keep it from reaching A
+        assert_eq!(
+            last_votes.into_iter().collect::<Vec<_>>(),
+            vec![("A".to_string(), 20), ("B".to_string(), 10), ("C".to_string(), 20)]
+        );
+
+        let mut untracked = BTreeMap::new();
+        let listing_order = Election {
+            tie_break: None,
+            ..election
+        };
+        add_last_vote(&mut untracked, &listing_order, &ranking(&["A", "B"], 10));
+        assert!(untracked.is_empty());
+    }
+
+    #[test]
+    fn earliest_voted_keeps_the_tied_order_for_votes_cast_at_the_same_time() {
+        let last_votes = [("A".to_string(), 5), ("B".to_string(), 5)];
+        let (chosen, _) = earliest_voted(&tie(&["A", "B"], 1), &last_votes);
+
+        assert_eq!(chosen, vec!["A"]);
+    }
+
+    #[test]
+    fn tie_breaker_leaves_winner_ties_open_without_a_policy_and_waits_for_draws() {
+        let tie = tie(&["A", "B"], 1);
+        for policy in [None, Some(TieBreak::LotDraw), Some(TieBreak::Random)] {
+            let breaker = TieBreaker {
+                policy,
+                ..TieBreaker::default()
+            };
+            assert!(breaker.decide(TieStage::Winners, &tie).is_none());
+        }
+
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::Random),
+            ..TieBreaker::default()
+        };
+        assert!(breaker.decide(TieStage::Elimination { round: 1 }, &tie).is_none());
     }
 
-    result_map
+    #[test]
+    fn tie_breaker_sends_winner_ties_to_a_runoff_and_breaks_round_ties_by_listing_order() {
+        let tie = tie(&["A", "B", "C"], 1);
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::Runoff),
+            ..TieBreaker::default()
+        };
+
+        let runoff = breaker.decide(TieStage::Winners, &tie).unwrap();
+        assert!(matches!(runoff.basis, TieBreakBasis::Runoff));
+        assert!(runoff.chosen.is_empty());
+
+        let eliminated = breaker.decide(TieStage::Elimination { round: 2 }, &tie).unwrap();
+        assert!(matches!(eliminated.basis, TieBreakBasis::ListingOrder));
+        assert_eq!(eliminated.chosen, vec!["C"]);
+
+        let elected = breaker.decide(TieStage::Election { round: 2 }, &tie).unwrap();
+        assert_eq!(elected.chosen, vec!["A"]);
+    }
+
+    #[test]
+    fn tie_breaker_eliminates_the_candidate_who_reached_the_tie_last() {
+        let last_votes = [("A".to_string(), 10), ("B".to_string(), 5)];
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::EarliestVote),
+            last_votes: &last_votes,
+            draws: &[],
+        };
+        let tie = tie(&["A", "B"], 1);
+
+        assert_eq!(
+            breaker.decide(TieStage::Elimination { round: 1 }, &tie).unwrap().chosen,
+            vec!["A"]
+        );
+        assert_eq!(breaker.decide(TieStage::Winners, &tie).unwrap().chosen, vec!["B"]);
+    }
+
+    #[test]
+    fn tie_breaker_follows_the_draw_made_for_the_same_tie() {
+        let tie = tie(&["A", "B"], 1);
+        let draws = [TieBreakDecision {
+            stage: TieStage::Elimination { round: 1 },
+            tie: tie.clone(),
+            basis: TieBreakBasis::Random {
+                randomness: vec![0; 32],
+            },
+            chosen: vec!["B".to_string()],
+        }];
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::Random),
+            last_votes: &[],
+            draws: &draws,
+        };
+
+        let decision = breaker.decide(TieStage::Elimination { round: 1 }, &tie).unwrap();
+        assert_eq!(decision.chosen, vec!["B"]);
+        // a tie in another round needs a draw of its own
+        assert!(breaker.decide(TieStage::Elimination { round: 2 }, &tie).is_none());
+    }
+
+    #[test]
+    fn ballot_results_add_up_the_weighted_answers_to_each_option() {
+        let election = Election {
+            id: 1,
+            voting_method: VotingMethod::Referendum,
+            ..Election::default()
+        };
+        crate::storage::BALLOTS.with(|service| {
+            service.borrow_mut().insert(
+                7,
+                crate::ballot_creation::Ballot {
+                    id: 7,
+                    election_id: 1,
+                    question: "Fund the park?".to_string(),
+                    options: vec!["yes".to_string(), "no".to_string()],
+                },
+            )
+        });
+        let answer = |voter_id: u64, option: &str, weight: u64| Vote {
+            id: voter_id,
+            voter_id,
+            candidate: String::new(),
+            election_id: 1,
+            timestamp: 0,
+            write_in: false,
+            selection: Selection::Answers(vec![(7, option.to_string())]),
+            weight,
+        };
+        let counts = count_votes(
+            &election,
+            [answer(1, "yes", 2), answer(2, "yes", 1), answer(3, "no", 1)],
+        );
+
+        let results = ballot_results(&election, &counts, &BTreeMap::new());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].tallies, vec![("yes".to_string(), 3), ("no".to_string(), 1)]);
+    }
 }
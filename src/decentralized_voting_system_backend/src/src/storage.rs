@@ -0,0 +1,218 @@
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::ballot_creation::Ballot;
+use crate::delegation::DelegationKey;
+use crate::election::Election;
+use crate::migration::MigrationState;
+use crate::result::{RunningTally, TallyKey, TallyOutcome};
+use crate::tabulation::TabulationState;
+use crate::tally_job::{RankingKey, RecountJob, TallyJob};
+use crate::user_management::{RoleGrant, RoleKey, Voter};
+use crate::voting::Vote;
+
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+type IdCell = Cell<u64, Memory>;
+
+// every stable struct of the canister lives here, each in its own virtual memory
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // the id counter every entity shared before each got its own sequence, zeroed once split
+    pub(crate) static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    pub(crate) static VOTES_STORAGE: RefCell<StableBTreeMap<u64, Vote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    pub(crate) static ELECTIONS: RefCell<StableBTreeMap<u64, Election, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    pub(crate) static VOTERS: RefCell<StableBTreeMap<u64, Voter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // maps the principal a voter registered with to their voter id
+    pub(crate) static VOTER_PRINCIPALS: RefCell<StableBTreeMap<StablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // maps (election_id, voter_id) to the id of the vote cast by that voter
+    pub(crate) static VOTES_BY_VOTER: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    pub(crate) static ROLES: RefCell<StableBTreeMap<RoleKey, RoleGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // maps (election_id, voter_id) to the voice credits a voter may spend in a quadratic election
+    pub(crate) static VOICE_CREDITS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // maps (election_id, principal) to the weight of that principal's vote, e.g. a share count.
+    // The entries of an election are its roll; elections without any take every registered voter
+    // with a weight of 1
+    pub(crate) static VOTER_WEIGHTS: RefCell<StableBTreeMap<(u64, StablePrincipal), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // maps a voter's delegation to the id of the voter they delegate to
+    pub(crate) static DELEGATIONS: RefCell<StableBTreeMap<DelegationKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    pub(crate) static BALLOTS: RefCell<StableBTreeMap<u64, Ballot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    pub(crate) static MIGRATION: RefCell<Cell<MigrationState, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))), MigrationState::default())
+            .expect("Cannot create the migration state")
+    );
+
+    // maps each Sequence to the next id it hands out
+    pub(crate) static ID_SEQUENCES: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // the secret vote ids are scrambled with, 0 until it is seeded from raw_rand
+    pub(crate) static VOTE_ID_KEY: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), 0)
+            .expect("Cannot create the vote id key")
+    );
+
+    // indexes votes by (election_id, vote_id), so an election's votes can be read without a scan
+    pub(crate) static VOTES_BY_ELECTION: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // the running tallies of each election, updated as votes are cast
+    pub(crate) static RUNNING_TALLIES: RefCell<StableBTreeMap<TallyKey, RunningTally, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // the tally job of each election that was tallied
+    pub(crate) static TALLY_JOBS: RefCell<StableBTreeMap<u64, TallyJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // maps each distinct ranking a running tally job counted to the summed weight of the votes that cast it
+    pub(crate) static TALLY_RANKINGS: RefCell<StableBTreeMap<RankingKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // the tabulation each running tally job is in the middle of, between its messages
+    pub(crate) static TABULATIONS: RefCell<StableBTreeMap<u64, TabulationState, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // indexes ballots by (election_id, ballot_id), so an election's ballots can be read without a scan
+    pub(crate) static BALLOTS_BY_ELECTION: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // the outcome stored by each election's tally job, kept out of ELECTIONS as it grows with the
+    // candidates and the rounds of the tabulations
+    pub(crate) static OUTCOMES: RefCell<StableBTreeMap<u64, TallyOutcome, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // the last recount of each election's running tallies
+    pub(crate) static RECOUNT_JOBS: RefCell<StableBTreeMap<u64, RecountJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+}
+
+// the entities numbered by their own id sequence
+#[derive(Clone, Copy)]
+pub(crate) enum Sequence {
+    Election = 0,
+    Voter = 1,
+    Ballot = 2,
+    // counts votes, whose public ids are scrambled by next_vote_id
+    Vote = 3,
+}
+
+// hands out the next id of an entity
+pub(crate) fn next_id(sequence: Sequence) -> u64 {
+    ID_SEQUENCES.with(|service| {
+        let mut service = service.borrow_mut();
+        let id = service.get(&(sequence as u8)).unwrap_or(0);
+        service.insert(sequence as u8, id + 1);
+        id
+    })
+}
+
+// vote ids have this bit set, keeping them apart from the ids votes had before they were scrambled
+pub(crate) const VOTE_ID_BIT: u64 = 1 << 63;
+
+// hands out an opaque id for the next vote, or None until the vote id key is seeded
+pub(crate) fn next_vote_id() -> Option<u64> {
+    let key = VOTE_ID_KEY.with(|cell| *cell.borrow().get());
+    if key == 0 {
+        return None;
+    }
+    Some(scramble_vote_id(next_id(Sequence::Vote), key))
+}
+
+// a keyed permutation of the low 63 bits, so vote ids are unique but reveal neither how many
+// votes were cast before nor in which order. Each step is invertible modulo 2^63.
+fn scramble_vote_id(sequence: u64, key: u64) -> u64 {
+    let mask = VOTE_ID_BIT - 1;
+    let mut id = sequence & mask;
+    for round in 0..4 {
+        id = (id ^ key.rotate_left(round * 16)) & mask;
+        id = id.wrapping_mul(0x9E37_79B9_7F4A_7C15) & mask;
+        id ^= id >> 29;
+    }
+    id | VOTE_ID_BIT
+}
+
+// a principal wrapper so it can be used as a key in a stable struct
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct StablePrincipal(pub(crate) Principal);
+
+impl Storable for StablePrincipal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self(Principal::from_slice(bytes.as_ref()))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
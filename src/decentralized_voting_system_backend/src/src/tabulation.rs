@@ -0,0 +1,1024 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::election::{Election, VotingMethod};
+use crate::result::{has_ranked_ballots, Tie, TieBreakDecision, TieBreaker, TieStage};
+
+// the work a tally job does per message, counted as a candidate compared against a ballot.
+// Keeps every step of a tabulation well within the instruction limit of a message.
+pub(crate) const TABULATION_BUDGET: u64 = 20_000_000;
+
+// the budget a ballot of the given ranking uses, an upper bound on what every tabulation does with it
+pub(crate) fn ballot_cost(ranking: &[String], candidates: &[String]) -> u64 {
+    (ranking.len() as u64 + 1) * candidates.len().max(1) as u64
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RankedChoiceRound {
+    pub(crate) round: u32,
+    // votes held by each continuing candidate, in Election.candidates order
+    pub(crate) tallies: Vec<(String, u64)>,
+    // ballots with no continuing candidate left
+    pub(crate) exhausted: u64,
+    pub(crate) eliminated: Option<String>,
+    // how the eliminated candidate was picked among those tied for last
+    pub(crate) tie_break: Option<TieBreakDecision>,
+    // where the eliminated candidate's ballots went; None means exhausted
+    pub(crate) transfers: Vec<(Option<String>, u64)>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RankedChoiceResult {
+    pub(crate) rounds: Vec<RankedChoiceRound>,
+    // None when no ballots were cast, the last candidates tied or a draw is awaited
+    pub(crate) winner: Option<String>,
+    // a tie the next round waits on a draw for; only set for results read before a tally
+    pub(crate) awaiting_draw: Option<(TieStage, Tie)>,
+}
+
+// STV tallies are kept in millionths of a vote so surplus transfers stay exact integers
+const VOTE_SCALE: u128 = 1_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StvRound {
+    pub(crate) round: u32,
+    // votes held by each continuing or elected candidate, in millionths of a vote
+    pub(crate) tallies: Vec<(String, u64)>,
+    // weight of ballots with no continuing candidate left, in millionths of a vote
+    pub(crate) exhausted: u64,
+    pub(crate) elected: Vec<String>,
+    pub(crate) eliminated: Option<String>,
+    // how the elected or eliminated candidate was picked among those tied
+    pub(crate) tie_break: Option<TieBreakDecision>,
+    // where the surplus or the eliminated candidate's ballots went; None means exhausted
+    pub(crate) transfers: Vec<(Option<String>, u64)>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StvResult {
+    pub(crate) seats: u32,
+    // Droop quota, in millionths of a vote
+    pub(crate) quota: u64,
+    pub(crate) rounds: Vec<StvRound>,
+    // in the order the candidates were elected
+    pub(crate) elected: Vec<String>,
+    // a tie the next round waits on a draw for; only set for results read before a tally
+    pub(crate) awaiting_draw: Option<(TieStage, Tie)>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SchulzeResult {
+    pub(crate) candidates: Vec<String>,
+    // pairwise[i][j] is the weight of the ballots ranking candidates[i] above candidates[j]
+    pub(crate) pairwise: Vec<Vec<u64>>,
+    // strength of the strongest path from candidates[i] to candidates[j]
+    pub(crate) strongest_paths: Vec<Vec<u64>>,
+    // the candidate who beats every other candidate head to head, if any
+    pub(crate) condorcet_winner: Option<String>,
+    // candidates no other candidate beats on strongest paths; several when tied
+    pub(crate) winners: Vec<String>,
+}
+
+// what a tabulation needs once it has read every ballot
+#[derive(Debug, PartialEq)]
+pub(crate) enum Step {
+    // another pass over the ballots
+    NextPass,
+    // more work without reading the ballots again
+    Continue,
+    // a draw for the tie, after which the pass ends again
+    AwaitingDraw(TieStage, Tie),
+    Done,
+}
+
+// the candidate picked among those tied in a round, with the decision that picked them, or the
+// step that waits for a draw
+fn pick_tied(
+    breaker: &TieBreaker,
+    stage: TieStage,
+    tied: Vec<String>,
+    votes: u64,
+) -> Result<(String, Option<TieBreakDecision>), Step> {
+    if tied.len() == 1 {
+        return Ok((tied[0].clone(), None));
+    }
+    let tie = Tie {
+        candidates: tied,
+        places: 1,
+        votes,
+    };
+    match breaker.decide(stage, &tie) {
+        Some(decision) => Ok((decision.chosen.first().cloned().unwrap_or_default(), Some(decision))),
+        None => Err(Step::AwaitingDraw(stage, tie)),
+    }
+}
+
+// instant runoff: eliminates the weakest candidate until one holds a majority of the
+// continuing ballots. Candidates tied for last are decided by the election's tie-break policy.
+// Each round is a pass over the ballots, which also counts where the previous round's
+// eliminated candidate's ballots went.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct InstantRunoff {
+    // in Election.candidates order
+    continuing: Vec<String>,
+    // eliminated by the last round, whose ballots the current pass transfers
+    eliminated: Option<String>,
+    // what the current pass counted
+    tallies: Vec<(String, u64)>,
+    exhausted: u64,
+    transfers: Vec<(Option<String>, u64)>,
+    result: RankedChoiceResult,
+}
+
+impl InstantRunoff {
+    pub(crate) fn new(candidates: &[String]) -> Self {
+        let mut tabulation = Self {
+            continuing: candidates.to_vec(),
+            eliminated: None,
+            tallies: Vec::new(),
+            exhausted: 0,
+            transfers: Vec::new(),
+            result: RankedChoiceResult {
+                rounds: Vec::new(),
+                winner: None,
+                awaiting_draw: None,
+            },
+        };
+        tabulation.start_pass();
+        tabulation
+    }
+
+    fn start_pass(&mut self) {
+        self.tallies = self.continuing.iter().map(|candidate| (candidate.clone(), 0)).collect();
+        self.exhausted = 0;
+        self.transfers = self
+            .continuing
+            .iter()
+            .map(|candidate| (Some(candidate.clone()), 0))
+            .chain(std::iter::once((None, 0)))
+            .collect();
+    }
+
+    fn add_ballot(&mut self, ballot: &[String], cast: u64) {
+        let choice = ballot.iter().find(|candidate| self.continuing.contains(candidate));
+        match choice {
+            Some(choice) => {
+                if let Some((_, votes)) = self.tallies.iter_mut().find(|(candidate, _)| candidate == choice) {
+                    *votes += cast;
+                }
+            }
+            None => self.exhausted += cast,
+        }
+
+        // the ballot moved if the eliminated candidate was its choice in the previous round
+        if let Some(eliminated) = &self.eliminated {
+            let previous = ballot
+                .iter()
+                .find(|candidate| *candidate == eliminated || self.continuing.contains(candidate));
+            if previous == Some(eliminated) {
+                let next = choice.cloned();
+                if let Some((_, votes)) = self.transfers.iter_mut().find(|(candidate, _)| *candidate == next) {
+                    *votes += cast;
+                }
+            }
+        }
+    }
+
+    // changes nothing before it returns AwaitingDraw, so the pass can end again once drawn
+    fn end_pass(&mut self, breaker: &TieBreaker) -> Step {
+        if let Some(round) = self.result.rounds.last_mut() {
+            round.transfers = self.transfers.iter().filter(|(_, votes)| *votes > 0).cloned().collect();
+        }
+
+        let round = self.result.rounds.len() as u32 + 1;
+        let active: u64 = self.tallies.iter().map(|(_, votes)| votes).sum();
+        let majority = self
+            .tallies
+            .iter()
+            .find(|(_, votes)| *votes * 2 > active)
+            .map(|(candidate, _)| candidate.clone());
+        let lowest = self.tallies.iter().map(|(_, votes)| *votes).min().unwrap_or(0);
+        let all_tied = self.tallies.iter().all(|(_, votes)| *votes == lowest);
+
+        if majority.is_some() || all_tied {
+            self.result.rounds.push(RankedChoiceRound {
+                round,
+                tallies: std::mem::take(&mut self.tallies),
+                exhausted: self.exhausted,
+                eliminated: None,
+                tie_break: None,
+                transfers: Vec::new(),
+            });
+            self.result.winner = majority;
+            return Step::Done;
+        }
+
+        let tied = self
+            .tallies
+            .iter()
+            .filter(|(_, votes)| *votes == lowest)
+            .map(|(candidate, _)| candidate.clone())
+            .collect();
+        let (eliminated, tie_break) = match pick_tied(breaker, TieStage::Elimination { round }, tied, lowest) {
+            Ok(picked) => picked,
+            Err(step) => return step,
+        };
+        self.continuing.retain(|candidate| *candidate != eliminated);
+        self.result.rounds.push(RankedChoiceRound {
+            round,
+            tallies: std::mem::take(&mut self.tallies),
+            exhausted: self.exhausted,
+            eliminated: Some(eliminated.clone()),
+            tie_break,
+            // filled in by the next pass
+            transfers: Vec::new(),
+        });
+        self.eliminated = Some(eliminated);
+        self.start_pass();
+        Step::NextPass
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Standing {
+    Hopeful,
+    Elected,
+    Excluded,
+}
+
+// what an STV round did to the ballots its candidate held, indexing Election.candidates
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum StvEvent {
+    // the ballots carry on at surplus / total of their weight
+    Elected { candidate: u32, surplus: u128, total: u128 },
+    Excluded { candidate: u32 },
+}
+
+// single transferable vote with a Droop quota and inclusive Gregory surplus transfers.
+// Each round elects the strongest candidate at or above quota, transferring every ballot
+// they hold at a reduced weight, or otherwise eliminates the weakest candidate. Ties for either
+// are decided by the election's tie-break policy. Weights are truncated integers, so every
+// replica computes the same result.
+// Each round is a pass over the ballots, which replays the rounds before it on every ballot to
+// find who holds it at what weight, so nothing is kept per ballot between passes. Once the last
+// seat is filled a closing pass counts where its ballots went.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SingleTransferableVote {
+    events: Vec<StvEvent>,
+    // what the current pass counted, in millionths of a vote: the weight each candidate holds,
+    // the exhausted weight, where the last event's ballots went and the votes cast
+    totals: Vec<u128>,
+    exhausted: u128,
+    transfers: Vec<(Option<u32>, u128)>,
+    cast: u128,
+    result: StvResult,
+}
+
+impl SingleTransferableVote {
+    pub(crate) fn new(candidates: &[String], seats: u32) -> Self {
+        let mut tabulation = Self {
+            events: Vec::new(),
+            totals: Vec::new(),
+            exhausted: 0,
+            transfers: Vec::new(),
+            cast: 0,
+            result: StvResult {
+                seats,
+                quota: 0,
+                rounds: Vec::new(),
+                elected: Vec::new(),
+                awaiting_draw: None,
+            },
+        };
+        tabulation.start_pass(candidates);
+        tabulation
+    }
+
+    fn start_pass(&mut self, candidates: &[String]) {
+        self.totals = vec![0; candidates.len()];
+        self.exhausted = 0;
+        self.transfers = Vec::new();
+        self.cast = 0;
+    }
+
+    fn add_ballot(&mut self, candidates: &[String], ballot: &[String], cast: u64) {
+        let preferences: Vec<usize> = ballot
+            .iter()
+            .filter_map(|name| candidates.iter().position(|candidate| candidate == name))
+            .collect();
+        let next_hopeful = |standings: &[Standing]| {
+            preferences
+                .iter()
+                .copied()
+                .find(|candidate| standings[*candidate] == Standing::Hopeful)
+        };
+
+        let mut standings = vec![Standing::Hopeful; candidates.len()];
+        let mut holder = preferences.first().copied();
+        // the weight of a single ballot of the group, which all transfer alike
+        let mut weight = VOTE_SCALE;
+        let mut moved = false;
+        for event in &self.events {
+            let (candidate, standing) = match *event {
+                StvEvent::Elected { candidate, .. } => (candidate as usize, Standing::Elected),
+                StvEvent::Excluded { candidate } => (candidate as usize, Standing::Excluded),
+            };
+            standings[candidate] = standing;
+            moved = holder == Some(candidate);
+            if moved {
+                if let StvEvent::Elected { surplus, total, .. } = *event {
+                    weight = weight * surplus / total;
+                }
+                holder = next_hopeful(&standings);
+            }
+        }
+
+        let carried = weight * cast as u128;
+        self.cast += cast as u128;
+        if moved {
+            let destination = holder.map(|candidate| candidate as u32);
+            match self
+                .transfers
+                .iter_mut()
+                .find(|(candidate, _)| *candidate == destination)
+            {
+                Some((_, total)) => *total += carried,
+                None => self.transfers.push((destination, carried)),
+            }
+        }
+        match holder {
+            Some(candidate) => self.totals[candidate] += carried,
+            None => self.exhausted += carried,
+        }
+    }
+
+    // changes nothing before it returns AwaitingDraw, so the pass can end again once drawn
+    fn end_pass(&mut self, candidates: &[String], breaker: &TieBreaker) -> Step {
+        let seats = self.result.seats;
+        let seats_to_fill = (seats as usize).min(candidates.len());
+        let quota = (self.cast / (seats as u128 + 1) + 1) * VOTE_SCALE;
+        self.result.quota = quota as u64;
+
+        if let Some(round) = self.result.rounds.last_mut() {
+            round.transfers = self
+                .transfers
+                .iter()
+                .filter(|(_, weight)| *weight > 0)
+                .map(|(candidate, weight)| {
+                    let candidate = candidate.map(|candidate| candidates[candidate as usize].clone());
+                    (candidate, *weight as u64)
+                })
+                .collect();
+        }
+        if self.result.elected.len() >= seats_to_fill {
+            return Step::Done;
+        }
+
+        // elected candidates keep the quota, hopefuls hold the ballots counted for them
+        let mut standings = vec![Standing::Hopeful; candidates.len()];
+        let mut totals = self.totals.clone();
+        for event in &self.events {
+            match *event {
+                StvEvent::Elected {
+                    candidate,
+                    surplus,
+                    total,
+                } => {
+                    standings[candidate as usize] = Standing::Elected;
+                    totals[candidate as usize] = total - surplus;
+                }
+                StvEvent::Excluded { candidate } => standings[candidate as usize] = Standing::Excluded,
+            }
+        }
+
+        let tallies = (0..candidates.len())
+            .filter(|candidate| standings[*candidate] != Standing::Excluded)
+            .map(|candidate| (candidates[candidate].clone(), totals[candidate] as u64))
+            .collect();
+        let mut round = StvRound {
+            round: self.result.rounds.len() as u32 + 1,
+            tallies,
+            exhausted: self.exhausted as u64,
+            elected: Vec::new(),
+            eliminated: None,
+            tie_break: None,
+            transfers: Vec::new(),
+        };
+
+        let mut hopefuls: Vec<usize> = (0..candidates.len())
+            .filter(|candidate| standings[*candidate] == Standing::Hopeful)
+            .collect();
+
+        // as many hopefuls as open seats: all of them are elected
+        if hopefuls.len() <= seats_to_fill - self.result.elected.len() {
+            hopefuls.sort_by(|a, b| totals[*b].cmp(&totals[*a]).then(a.cmp(b)));
+            for candidate in hopefuls {
+                self.result.elected.push(candidates[candidate].clone());
+                round.elected.push(candidates[candidate].clone());
+            }
+            self.result.rounds.push(round);
+            return Step::Done;
+        }
+
+        let highest = hopefuls.iter().map(|candidate| totals[*candidate]).max().unwrap_or(0);
+        let elected = highest >= quota;
+        let (stage, votes) = if elected {
+            (TieStage::Election { round: round.round }, highest)
+        } else {
+            let lowest = hopefuls.iter().map(|candidate| totals[*candidate]).min().unwrap_or(0);
+            (TieStage::Elimination { round: round.round }, lowest)
+        };
+        let tied = hopefuls
+            .iter()
+            .filter(|candidate| totals[**candidate] == votes)
+            .map(|candidate| candidates[*candidate].clone())
+            .collect();
+        let (picked, tie_break) = match pick_tied(breaker, stage, tied, votes as u64) {
+            Ok(picked) => picked,
+            Err(step) => return step,
+        };
+        let candidate = candidates.iter().position(|name| *name == picked).unwrap_or_default();
+        round.tie_break = tie_break;
+
+        if elected {
+            self.events.push(StvEvent::Elected {
+                candidate: candidate as u32,
+                surplus: votes - quota,
+                total: votes,
+            });
+            self.result.elected.push(picked.clone());
+            round.elected.push(picked);
+        } else {
+            self.events.push(StvEvent::Excluded {
+                candidate: candidate as u32,
+            });
+            round.eliminated = Some(picked);
+        }
+
+        // transfers are filled in by the next pass
+        self.result.rounds.push(round);
+        self.start_pass(candidates);
+        Step::NextPass
+    }
+}
+
+// Schulze method. A ranked candidate is preferred over every unranked one, and unranked
+// candidates are tied with each other. A pass over the ballots counts the pairwise preferences,
+// then the strongest paths are widened through a budget of candidates per message.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Schulze {
+    pairwise: Vec<Vec<u64>>,
+    // set once every ballot is counted
+    strongest_paths: Vec<Vec<u64>>,
+    // the candidate the strongest paths are widened through next
+    next_candidate: u32,
+}
+
+impl Schulze {
+    pub(crate) fn new(candidates: &[String]) -> Self {
+        Self {
+            pairwise: vec![vec![0; candidates.len()]; candidates.len()],
+            strongest_paths: Vec::new(),
+            next_candidate: 0,
+        }
+    }
+
+    fn add_ballot(&mut self, candidates: &[String], ballot: &[String], cast: u64) {
+        let mut rank = vec![usize::MAX; candidates.len()];
+        let mut ranked = Vec::new();
+        for (position, name) in ballot.iter().enumerate() {
+            if let Some(candidate) = candidates.iter().position(|candidate| candidate == name) {
+                if rank[candidate] == usize::MAX {
+                    rank[candidate] = position;
+                    ranked.push(candidate);
+                }
+            }
+        }
+        for i in ranked {
+            for (j, wins) in self.pairwise[i].iter_mut().enumerate() {
+                if rank[i] < rank[j] {
+                    *wins += cast;
+                }
+            }
+        }
+    }
+
+    fn end_pass(&mut self, candidates: &[String]) -> Step {
+        let count = candidates.len();
+        let pairwise = &self.pairwise;
+        if self.strongest_paths.is_empty() {
+            self.strongest_paths = (0..count)
+                .map(|i| {
+                    (0..count)
+                        .map(|j| {
+                            if pairwise[i][j] > pairwise[j][i] {
+                                pairwise[i][j]
+                            } else {
+                                0
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+        }
+
+        let per_message = (TABULATION_BUDGET / (count as u64 * count as u64).max(1)).max(1);
+        let paths = &mut self.strongest_paths;
+        for k in (self.next_candidate as usize..count).take(per_message as usize) {
+            for i in 0..count {
+                for j in 0..count {
+                    if i != j && i != k && j != k {
+                        let through_k = paths[i][k].min(paths[k][j]);
+                        if through_k > paths[i][j] {
+                            paths[i][j] = through_k;
+                        }
+                    }
+                }
+            }
+            self.next_candidate = k as u32 + 1;
+        }
+
+        if (self.next_candidate as usize) < count {
+            Step::Continue
+        } else {
+            Step::Done
+        }
+    }
+
+    fn into_result(self, candidates: &[String]) -> SchulzeResult {
+        let count = candidates.len();
+        let (pairwise, strongest_paths) = (self.pairwise, self.strongest_paths);
+        let condorcet_winner = (0..count)
+            .find(|i| (0..count).all(|j| *i == j || pairwise[*i][j] > pairwise[j][*i]))
+            .map(|i| candidates[i].clone());
+        let winners = (0..count)
+            .filter(|i| (0..count).all(|j| strongest_paths[*i][j] >= strongest_paths[j][*i]))
+            .map(|i| candidates[i].clone())
+            .collect();
+
+        SchulzeResult {
+            candidates: candidates.to_vec(),
+            pairwise,
+            strongest_paths,
+            condorcet_winner,
+            winners,
+        }
+    }
+}
+
+// a tabulation of ranked ballots, advanced a pass at a time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum Tabulation {
+    InstantRunoff(InstantRunoff),
+    SingleTransferableVote(SingleTransferableVote),
+    Schulze(Schulze),
+}
+
+impl Tabulation {
+    pub(crate) fn add_ballot(&mut self, candidates: &[String], ballot: &[String], cast: u64) {
+        match self {
+            Tabulation::InstantRunoff(tabulation) => tabulation.add_ballot(ballot, cast),
+            Tabulation::SingleTransferableVote(tabulation) => tabulation.add_ballot(candidates, ballot, cast),
+            Tabulation::Schulze(tabulation) => tabulation.add_ballot(candidates, ballot, cast),
+        }
+    }
+
+    // called once every ballot of a pass is added, and again after Continue or a draw
+    pub(crate) fn end_pass(&mut self, candidates: &[String], breaker: &TieBreaker) -> Step {
+        match self {
+            Tabulation::InstantRunoff(tabulation) => tabulation.end_pass(breaker),
+            Tabulation::SingleTransferableVote(tabulation) => tabulation.end_pass(candidates, breaker),
+            Tabulation::Schulze(tabulation) => tabulation.end_pass(candidates),
+        }
+    }
+}
+
+// the tabulations of an election's ranked ballots, run one after another by its tally job and
+// kept in stable memory between its messages
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TabulationState {
+    // None once every tabulation the election needs is done
+    pub(crate) running: Option<Tabulation>,
+    pub(crate) ranked_choice: Option<RankedChoiceResult>,
+    pub(crate) stv: Option<StvResult>,
+    pub(crate) schulze: Option<SchulzeResult>,
+}
+
+impl Storable for TabulationState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl TabulationState {
+    // ranked choice and STV elections are tabulated by their method first, then every election
+    // with ranked ballots by Schulze
+    pub(crate) fn new(election: &Election) -> Self {
+        let running = match election.voting_method {
+            VotingMethod::RankedChoice => Some(Tabulation::InstantRunoff(InstantRunoff::new(&election.candidates))),
+            VotingMethod::SingleTransferableVote => Some(Tabulation::SingleTransferableVote(
+                SingleTransferableVote::new(&election.candidates, election.seats),
+            )),
+            _ if has_ranked_ballots(election) => Some(Tabulation::Schulze(Schulze::new(&election.candidates))),
+            _ => None,
+        };
+        Self {
+            running,
+            ranked_choice: None,
+            stv: None,
+            schulze: None,
+        }
+    }
+
+    // keeps the result of the running tabulation, which is done, and starts the next one
+    pub(crate) fn finish_running(&mut self, candidates: &[String]) {
+        self.running = match self.running.take() {
+            Some(Tabulation::InstantRunoff(tabulation)) => {
+                self.ranked_choice = Some(tabulation.result);
+                Some(Tabulation::Schulze(Schulze::new(candidates)))
+            }
+            Some(Tabulation::SingleTransferableVote(tabulation)) => {
+                self.stv = Some(tabulation.result);
+                Some(Tabulation::Schulze(Schulze::new(candidates)))
+            }
+            Some(Tabulation::Schulze(tabulation)) => {
+                self.schulze = Some(tabulation.into_result(candidates));
+                None
+            }
+            None => None,
+        };
+    }
+}
+
+// tabulates every ballot at once, for results read before a tally, stopping at a tie that
+// waits for a draw
+pub(crate) fn tabulate_instant_runoff(
+    candidates: &[String],
+    ballots: &[(Vec<String>, u64)],
+    breaker: &TieBreaker,
+) -> RankedChoiceResult {
+    let mut tabulation = InstantRunoff::new(candidates);
+    loop {
+        for (ballot, cast) in ballots {
+            tabulation.add_ballot(ballot, *cast);
+        }
+        match tabulation.end_pass(breaker) {
+            Step::AwaitingDraw(stage, tie) => tabulation.result.awaiting_draw = Some((stage, tie)),
+            Step::Done => {}
+            _ => continue,
+        }
+        return tabulation.result;
+    }
+}
+
+pub(crate) fn tabulate_single_transferable_vote(
+    candidates: &[String],
+    seats: u32,
+    ballots: &[(Vec<String>, u64)],
+    breaker: &TieBreaker,
+) -> StvResult {
+    let mut tabulation = SingleTransferableVote::new(candidates, seats);
+    loop {
+        for (ballot, cast) in ballots {
+            tabulation.add_ballot(candidates, ballot, *cast);
+        }
+        match tabulation.end_pass(candidates, breaker) {
+            Step::AwaitingDraw(stage, tie) => tabulation.result.awaiting_draw = Some((stage, tie)),
+            Step::Done => {}
+            _ => continue,
+        }
+        return tabulation.result;
+    }
+}
+
+pub(crate) fn tabulate_schulze(candidates: &[String], ballots: &[(Vec<String>, u64)]) -> SchulzeResult {
+    let mut tabulation = Schulze::new(candidates);
+    for (ballot, cast) in ballots {
+        tabulation.add_ballot(candidates, ballot, *cast);
+    }
+    while tabulation.end_pass(candidates) == Step::Continue {}
+    tabulation.into_result(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::TieBreak;
+    use crate::result::TieBreakBasis;
+    use candid::Principal;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn ballots(ballots: &[(&[&str], u64)]) -> Vec<(Vec<String>, u64)> {
+        ballots.iter().map(|(ranking, cast)| (names(ranking), *cast)).collect()
+    }
+
+    #[test]
+    fn instant_runoff_elects_a_first_round_majority() {
+        let result = tabulate_instant_runoff(
+            &names(&["A", "B"]),
+            &ballots(&[(&["A"], 3), (&["B", "A"], 1)]),
+            &TieBreaker::default(),
+        );
+
+        assert_eq!(result.winner.as_deref(), Some("A"));
+        assert_eq!(result.rounds.len(), 1);
+        assert_eq!(
+            result.rounds[0].tallies,
+            vec![("A".to_string(), 3), ("B".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn instant_runoff_transfers_and_exhausts_eliminated_ballots() {
+        let result = tabulate_instant_runoff(
+            &names(&["A", "B", "C"]),
+            &ballots(&[(&["A"], 5), (&["B"], 3), (&["C", "B"], 1), (&["C"], 1)]),
+            &TieBreaker::default(),
+        );
+
+        assert_eq!(result.rounds[0].eliminated.as_deref(), Some("C"));
+        assert_eq!(result.rounds[0].transfers, vec![(Some("B".to_string()), 1), (None, 1)]);
+        assert_eq!(
+            result.rounds[1].tallies,
+            vec![("A".to_string(), 5), ("B".to_string(), 4)]
+        );
+        assert_eq!(result.rounds[1].exhausted, 1);
+        assert_eq!(result.winner.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn instant_runoff_eliminates_the_candidate_listed_last_among_those_tied_for_last() {
+        let result = tabulate_instant_runoff(
+            &names(&["A", "B", "C"]),
+            &ballots(&[(&["A"], 2), (&["B"], 1), (&["C"], 1)]),
+            &TieBreaker::default(),
+        );
+
+        assert_eq!(result.rounds[0].eliminated.as_deref(), Some("C"));
+        assert!(matches!(
+            result.rounds[0].tie_break.as_ref().map(|decision| &decision.basis),
+            Some(TieBreakBasis::ListingOrder)
+        ));
+        assert_eq!(result.winner.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn instant_runoff_eliminates_by_the_election_policy_and_records_the_decision() {
+        let last_votes = [("B".to_string(), 20), ("C".to_string(), 10)];
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::EarliestVote),
+            last_votes: &last_votes,
+            draws: &[],
+        };
+        let result = tabulate_instant_runoff(
+            &names(&["A", "B", "C"]),
+            &ballots(&[(&["A"], 3), (&["B", "C"], 2), (&["C"], 2)]),
+            &breaker,
+        );
+
+        // B reached the tied total last, so goes out instead of C, who is listed last
+        assert_eq!(result.rounds[0].eliminated.as_deref(), Some("B"));
+        let decision = result.rounds[0].tie_break.as_ref().expect("B and C tie for last");
+        assert_eq!(decision.stage, TieStage::Elimination { round: 1 });
+        assert_eq!(decision.tie.candidates, names(&["B", "C"]));
+        assert!(matches!(decision.basis, TieBreakBasis::EarliestVote { .. }));
+        assert_eq!(result.winner.as_deref(), Some("C"));
+    }
+
+    #[test]
+    fn instant_runoff_stops_at_a_tie_left_to_a_draw_and_follows_the_draw() {
+        let candidates = names(&["A", "B", "C"]);
+        let cast = ballots(&[(&["A"], 3), (&["B"], 2), (&["C"], 2)]);
+        let waiting = tabulate_instant_runoff(
+            &candidates,
+            &cast,
+            &TieBreaker {
+                policy: Some(TieBreak::LotDraw),
+                ..TieBreaker::default()
+            },
+        );
+
+        let tie = Tie {
+            candidates: names(&["B", "C"]),
+            places: 1,
+            votes: 2,
+        };
+        assert_eq!(
+            waiting.awaiting_draw,
+            Some((TieStage::Elimination { round: 1 }, tie.clone()))
+        );
+        assert!(waiting.rounds.is_empty());
+        assert_eq!(waiting.winner, None);
+
+        let draws = [TieBreakDecision {
+            stage: TieStage::Elimination { round: 1 },
+            tie,
+            basis: TieBreakBasis::LotDraw {
+                recorded_by: Principal::anonymous(),
+            },
+            chosen: names(&["B"]),
+        }];
+        let drawn = tabulate_instant_runoff(
+            &candidates,
+            &cast,
+            &TieBreaker {
+                policy: Some(TieBreak::LotDraw),
+                last_votes: &[],
+                draws: &draws,
+            },
+        );
+        assert_eq!(drawn.awaiting_draw, None);
+        assert_eq!(drawn.rounds[0].eliminated.as_deref(), Some("B"));
+        assert!(matches!(
+            drawn.rounds[0].tie_break.as_ref().map(|decision| &decision.basis),
+            Some(TieBreakBasis::LotDraw { .. })
+        ));
+        assert_eq!(drawn.winner.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn instant_runoff_has_no_winner_when_the_last_candidates_tie_or_nobody_voted() {
+        let tied = tabulate_instant_runoff(
+            &names(&["A", "B"]),
+            &ballots(&[(&["A"], 1), (&["B"], 1)]),
+            &TieBreaker::default(),
+        );
+        assert_eq!(tied.winner, None);
+        assert_eq!(tied.rounds.len(), 1);
+
+        let empty = tabulate_instant_runoff(&names(&["A", "B"]), &[], &TieBreaker::default());
+        assert_eq!(empty.winner, None);
+    }
+
+    #[test]
+    fn single_transferable_vote_transfers_surplus_at_a_reduced_weight() {
+        let result = tabulate_single_transferable_vote(
+            &names(&["A", "B", "C"]),
+            2,
+            &ballots(&[(&["A", "B"], 6), (&["C"], 2), (&["B"], 1)]),
+            &TieBreaker::default(),
+        );
+
+        // 9 ballots for 2 seats
+        assert_eq!(result.quota, 4_000_000);
+        assert_eq!(result.rounds[0].elected, names(&["A"]));
+        // each of A's 6 ballots carries 2/6 of a vote on to B
+        assert_eq!(result.rounds[0].transfers, vec![(Some("B".to_string()), 1_999_998)]);
+        assert_eq!(result.rounds[1].eliminated.as_deref(), Some("C"));
+        assert_eq!(result.elected, names(&["A", "B"]));
+    }
+
+    #[test]
+    fn single_transferable_vote_exhausts_ballots_and_eliminates_the_candidate_listed_last_among_those_tied_for_last() {
+        let result = tabulate_single_transferable_vote(
+            &names(&["A", "B", "C"]),
+            1,
+            &ballots(&[(&["A"], 2), (&["B"], 1), (&["C"], 1)]),
+            &TieBreaker::default(),
+        );
+
+        assert_eq!(result.quota, 3_000_000);
+        assert_eq!(result.rounds[0].eliminated.as_deref(), Some("C"));
+        assert!(matches!(
+            result.rounds[0].tie_break.as_ref().map(|decision| &decision.basis),
+            Some(TieBreakBasis::ListingOrder)
+        ));
+        assert_eq!(result.rounds[0].transfers, vec![(None, 1_000_000)]);
+        assert_eq!(result.rounds[1].exhausted, 1_000_000);
+        assert_eq!(result.elected, names(&["A"]));
+    }
+
+    #[test]
+    fn single_transferable_vote_elects_the_remaining_hopefuls_when_they_fit_the_open_seats() {
+        let result =
+            tabulate_single_transferable_vote(&names(&["A", "B"]), 2, &ballots(&[(&["B"], 1)]), &TieBreaker::default());
+
+        assert_eq!(result.rounds.len(), 1);
+        assert_eq!(result.elected, names(&["B", "A"]));
+    }
+
+    #[test]
+    fn single_transferable_vote_moves_ballots_by_replaying_the_earlier_rounds() {
+        let result = tabulate_single_transferable_vote(
+            &names(&["A", "B", "C", "D"]),
+            2,
+            &ballots(&[(&["A"], 5), (&["B"], 3), (&["C"], 1), (&["D", "B"], 2)]),
+            &TieBreaker::default(),
+        );
+
+        assert_eq!(result.quota, 4_000_000);
+        assert_eq!(result.rounds[0].elected, names(&["A"]));
+        assert_eq!(result.rounds[0].transfers, vec![(None, 1_000_000)]);
+        assert_eq!(result.rounds[1].eliminated.as_deref(), Some("C"));
+        // D's ballots move to B only once A is elected and C excluded
+        assert_eq!(result.rounds[2].eliminated.as_deref(), Some("D"));
+        assert_eq!(result.rounds[2].transfers, vec![(Some("B".to_string()), 2_000_000)]);
+        // A keeps the quota after its surplus moved on
+        assert_eq!(
+            result.rounds[3].tallies,
+            vec![("A".to_string(), 4_000_000), ("B".to_string(), 5_000_000)]
+        );
+        assert_eq!(result.rounds[3].exhausted, 2_000_000);
+        assert_eq!(result.elected, names(&["A", "B"]));
+    }
+
+    #[test]
+    fn single_transferable_vote_elects_by_the_election_policy_among_candidates_tied_at_quota() {
+        let last_votes = [("A".to_string(), 20), ("B".to_string(), 10)];
+        let breaker = TieBreaker {
+            policy: Some(TieBreak::EarliestVote),
+            last_votes: &last_votes,
+            draws: &[],
+        };
+        let result = tabulate_single_transferable_vote(
+            &names(&["A", "B", "C"]),
+            2,
+            &ballots(&[(&["A"], 3), (&["B"], 3), (&["C"], 1)]),
+            &breaker,
+        );
+
+        assert_eq!(result.quota, 3_000_000);
+        assert_eq!(result.rounds[0].elected, names(&["B"]));
+        let decision = result.rounds[0].tie_break.as_ref().expect("A and B tie at quota");
+        assert_eq!(decision.stage, TieStage::Election { round: 1 });
+        assert_eq!(decision.tie.votes, 3_000_000);
+        assert_eq!(result.elected, names(&["B", "A"]));
+    }
+
+    #[test]
+    fn single_transferable_vote_stops_at_a_tie_left_to_raw_rand() {
+        let result = tabulate_single_transferable_vote(
+            &names(&["A", "B", "C"]),
+            1,
+            &ballots(&[(&["A"], 2), (&["B"], 1), (&["C"], 1)]),
+            &TieBreaker {
+                policy: Some(TieBreak::Random),
+                ..TieBreaker::default()
+            },
+        );
+
+        assert_eq!(
+            result.awaiting_draw.map(|(stage, tie)| (stage, tie.candidates)),
+            Some((TieStage::Elimination { round: 1 }, names(&["B", "C"])))
+        );
+        assert!(result.elected.is_empty());
+    }
+
+    #[test]
+    fn schulze_finds_the_winner_without_a_condorcet_winner() {
+        // the example of Schulze's paper: 45 voters, E wins on strongest paths
+        let result = tabulate_schulze(
+            &names(&["A", "B", "C", "D", "E"]),
+            &ballots(&[
+                (&["A", "C", "B", "E", "D"], 5),
+                (&["A", "D", "E", "C", "B"], 5),
+                (&["B", "E", "D", "A", "C"], 8),
+                (&["C", "A", "B", "E", "D"], 3),
+                (&["C", "A", "E", "B", "D"], 7),
+                (&["C", "B", "A", "D", "E"], 2),
+                (&["D", "C", "E", "B", "A"], 7),
+                (&["E", "B", "A", "D", "C"], 8),
+            ]),
+        );
+
+        assert_eq!(result.pairwise[0], vec![0, 20, 26, 30, 22]);
+        assert_eq!(result.strongest_paths[4], vec![25, 28, 28, 31, 0]);
+        assert_eq!(result.condorcet_winner, None);
+        assert_eq!(result.winners, names(&["E"]));
+    }
+
+    #[test]
+    fn schulze_elects_the_condorcet_winner_and_prefers_ranked_over_unranked_candidates() {
+        let result = tabulate_schulze(
+            &names(&["A", "B", "C"]),
+            &ballots(&[(&["A", "B", "C"], 3), (&["B", "A", "C"], 2), (&["C"], 1)]),
+        );
+
+        assert_eq!(result.pairwise[0], vec![0, 3, 5]);
+        // the ballot ranking only C puts C above A and B, and neither of them above the other
+        assert_eq!(result.pairwise[1], vec![2, 0, 5]);
+        assert_eq!(result.pairwise[2], vec![1, 1, 0]);
+        assert_eq!(result.condorcet_winner.as_deref(), Some("A"));
+        assert_eq!(result.winners, names(&["A"]));
+    }
+
+    #[test]
+    fn schulze_reports_every_candidate_tied_on_strongest_paths() {
+        let result = tabulate_schulze(&names(&["A", "B"]), &ballots(&[(&["A"], 1), (&["B"], 1)]));
+
+        assert_eq!(result.condorcet_winner, None);
+        assert_eq!(result.winners, names(&["A", "B"]));
+    }
+}
@@ -0,0 +1,668 @@
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::time;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::delegation::{first_delegation, resolve_delegations_from, DelegatedWeight, DelegationKey};
+use crate::election::{
+    _get_election, caller_administered_election, do_insert_election, Election, ElectionStatus, TieBreak,
+};
+use crate::migration::{has_sequential_vote_ids, votes_indexed};
+use crate::result::{
+    _get_running_tallies, add_delegated_vote, add_last_vote, add_vote, break_tie, caller_viewable_results,
+    do_insert_outcome, drawn_by_lot, election_outcome, replace_running_tallies, RunningTally, TallyEntry,
+    TallyMismatch, Tie, TieBreakBasis, TieBreakDecision, TieBreaker, TieStage,
+};
+use crate::storage::{RECOUNT_JOBS, TABULATIONS, TALLY_JOBS, TALLY_RANKINGS, VOTES_BY_ELECTION};
+use crate::tabulation::{ballot_cost, Step, Tabulation, TabulationState, TABULATION_BUDGET};
+use crate::user_management::caller_principal;
+use crate::voting::{_get_vote, _get_voter_vote, Selection, Vote};
+use crate::Error;
+
+// votes read, or rankings removed, per message by a tally or recount job
+const TALLY_CHUNK_SIZE: usize = 1_000;
+
+// a job that has not made progress for this long has stopped, e.g. on a trap, and can be restarted
+const TALLY_STALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum TallyJobStatus {
+    // reading the election's votes, a chunk per message
+    Counting,
+    // following the delegation chains of the voters who did not vote, a chunk of delegations per message
+    ResolvingDelegations,
+    // computing the outcome from what was counted
+    Tabulating,
+    // a tie the election breaks with randomness, being fetched from raw_rand
+    AwaitingRandomness,
+    // a tie the election breaks by lot, waiting for an admin to record the draw
+    AwaitingLotDraw,
+    // removing the rankings counted, once the outcome is stored or before a restarted job counts again
+    CleaningUp,
+    // the outcome is stored
+    Done,
+}
+
+// the progress of tallying a closed election, kept in stable memory so the job survives upgrades
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TallyJob {
+    pub(crate) election_id: u64,
+    pub(crate) status: TallyJobStatus,
+    pub(crate) votes_counted: u64,
+    // the votes cast in the election, unknown for elections without running tallies
+    pub(crate) votes_cast: Option<u64>,
+    pub(crate) started_at: u64,
+    // when the job last made progress, which tells a job that stopped apart from a running one
+    pub(crate) updated_at: u64,
+    pub(crate) finished_at: Option<u64>,
+    // the vote id the next chunk starts at
+    pub(crate) next_vote_id: u64,
+    // the ranking the tabulation pass reads next, None once the pass has read them all
+    pub(crate) next_ranking: Option<Vec<String>>,
+    // the direct tallies of the votes counted so far
+    pub(crate) counts: Vec<(TallyEntry, RunningTally)>,
+    // the delegation the next chunk of delegations starts at
+    pub(crate) next_delegation: Option<DelegationKey>,
+    // what the weight delegated so far adds to the tallies of the votes it reached
+    pub(crate) delegated_counts: Vec<(TallyEntry, RunningTally)>,
+    // when each candidate last received a vote, for the EarliestVote tie-break
+    pub(crate) last_votes: Vec<(String, u64)>,
+    // the tie the job is waiting on a draw for
+    pub(crate) pending_tie: Option<(TieStage, Tie)>,
+    // the draws made for the ties met so far, which the tabulations and the winners then follow
+    pub(crate) draws: Vec<TieBreakDecision>,
+}
+
+impl Storable for TallyJob {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// the progress of recounting an election's votes, kept in stable memory so the job survives
+// upgrades. Its running tallies are replaced with the recount once every vote is counted.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecountJob {
+    pub(crate) election_id: u64,
+    pub(crate) votes_counted: u64,
+    pub(crate) started_at: u64,
+    // when the job last made progress, which tells a job that stopped apart from a running one
+    pub(crate) updated_at: u64,
+    pub(crate) finished_at: Option<u64>,
+    // the vote id the next chunk starts at
+    pub(crate) next_vote_id: u64,
+    // the tallies of the votes recounted so far
+    pub(crate) counts: Vec<(TallyEntry, RunningTally)>,
+    // the entries whose running tallies differed from the recount, once it is finished
+    pub(crate) mismatches: Vec<TallyMismatch>,
+}
+
+impl Storable for RecountJob {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// a distinct ranking counted by a running tally job
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct RankingKey {
+    pub(crate) election_id: u64,
+    pub(crate) ranking: Vec<String>,
+}
+
+impl Storable for RankingKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// starts tallying a closed election. The votes and then the delegations are read in chunks over
+// several messages, and the ranked ballots tabulated in passes; then the outcome is stored and the
+// election moves to Tallied. A job that stopped making progress can be started again.
+#[ic_cdk::update]
+fn tally_election(election_id: u64) -> Result<TallyJob, Error> {
+    let election = caller_administered_election(election_id)?;
+
+    if !election.status.can_transition_to(ElectionStatus::Tallied) {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "election with id={} cannot move from {:?} to {:?}",
+                election_id,
+                election.status,
+                ElectionStatus::Tallied
+            ),
+        });
+    }
+
+    let previous = _get_tally_job(election_id);
+    if let Some(job) = &previous {
+        let stalled = time().saturating_sub(job.updated_at) > TALLY_STALL_TIMEOUT.as_nanos() as u64;
+        if job.status == TallyJobStatus::AwaitingLotDraw || (job.status != TallyJobStatus::Done && !stalled) {
+            return Err(Error::InvalidTransition {
+                msg: format!("election with id={} is already being tallied", election_id),
+            });
+        }
+    }
+
+    // a restarted job first removes what the previous one counted
+    TABULATIONS.with(|service| service.borrow_mut().remove(&election_id));
+    let mut job = TallyJob {
+        election_id,
+        status: if previous.is_some() {
+            TallyJobStatus::CleaningUp
+        } else {
+            TallyJobStatus::Counting
+        },
+        votes_counted: 0,
+        votes_cast: _get_running_tallies(election_id)
+            .and_then(|counts| counts.get(&TallyEntry::Turnout).map(|turnout| turnout.headcount)),
+        started_at: time(),
+        updated_at: time(),
+        finished_at: None,
+        next_vote_id: 0,
+        next_ranking: None,
+        counts: Vec::new(),
+        next_delegation: None,
+        delegated_counts: Vec::new(),
+        last_votes: Vec::new(),
+        pending_tie: None,
+        draws: Vec::new(),
+    };
+    do_insert_tally_job(&mut job);
+    ic_cdk_timers::set_timer(Duration::ZERO, move || run_tally_job(election_id));
+    Ok(job)
+}
+
+#[ic_cdk::query]
+fn get_tally_job(election_id: u64) -> Result<TallyJob, Error> {
+    caller_viewable_results(election_id)?;
+    _get_tally_job(election_id).ok_or(Error::NotFound {
+        msg: format!("election with id={} has not been tallied", election_id),
+    })
+}
+
+// timers do not survive an upgrade, so the jobs still running are scheduled again
+pub(crate) fn resume_tally_jobs() {
+    let running: Vec<u64> = TALLY_JOBS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, job)| job.status != TallyJobStatus::Done)
+            .map(|(election_id, _)| election_id)
+            .collect()
+    });
+    for election_id in running {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_tally_job(election_id));
+    }
+
+    let recounting: Vec<u64> = RECOUNT_JOBS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, job)| job.finished_at.is_none())
+            .map(|(election_id, _)| election_id)
+            .collect()
+    });
+    for election_id in recounting {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_recount_job(election_id));
+    }
+}
+
+// runs the next step of a tally job and schedules the one after
+fn run_tally_job(election_id: u64) {
+    let (Some(mut job), Some(mut election)) = (_get_tally_job(election_id), _get_election(&election_id)) else {
+        return;
+    };
+
+    // the election index is still being built after an upgrade, or votes are being rekeyed, which
+    // moves them within it
+    if !votes_indexed() || has_sequential_vote_ids() {
+        ic_cdk_timers::set_timer(Duration::from_secs(1), move || run_tally_job(election_id));
+        return;
+    }
+
+    match job.status {
+        TallyJobStatus::Counting => count_step(&mut job, &election),
+        TallyJobStatus::ResolvingDelegations => resolve_chunk(&mut job, &election),
+        TallyJobStatus::Tabulating => tabulate_step(&mut job, &mut election),
+        TallyJobStatus::AwaitingRandomness => {
+            ic_cdk::spawn(draw_tied_places(election_id));
+            return;
+        }
+        TallyJobStatus::CleaningUp => {
+            if !remove_rankings(election_id) {
+                job.status = if job.finished_at.is_some() {
+                    TallyJobStatus::Done
+                } else {
+                    TallyJobStatus::Counting
+                };
+            }
+        }
+        TallyJobStatus::AwaitingLotDraw | TallyJobStatus::Done => return,
+    }
+
+    do_insert_tally_job(&mut job);
+    if !matches!(job.status, TallyJobStatus::AwaitingLotDraw | TallyJobStatus::Done) {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_tally_job(election_id));
+    }
+}
+
+// advances the tabulation of the ranked ballots by a message's budget of work, and once every
+// tabulation is done stores the outcome
+fn tabulate_step(job: &mut TallyJob, election: &mut Election) {
+    let election_id = job.election_id;
+    let mut state = _get_tabulation(election_id).unwrap_or_else(|| TabulationState::new(election));
+    let (last_votes, draws) = (job.last_votes.clone(), job.draws.clone());
+    let breaker = TieBreaker {
+        policy: election.tie_break,
+        last_votes: &last_votes,
+        draws: &draws,
+    };
+
+    if let Some(tabulation) = state.running.as_mut() {
+        if let Some(from) = job.next_ranking.take() {
+            job.next_ranking = read_rankings(election_id, &election.candidates, tabulation, from);
+        }
+        if job.next_ranking.is_none() {
+            match tabulation.end_pass(&election.candidates, &breaker) {
+                Step::NextPass => job.next_ranking = Some(Vec::new()),
+                Step::Continue => {}
+                // the pass ends again once the tie is drawn
+                Step::AwaitingDraw(stage, tie) => await_draw(job, election.tie_break, stage, tie),
+                Step::Done => {
+                    state.finish_running(&election.candidates);
+                    job.next_ranking = Some(Vec::new());
+                }
+            }
+        }
+        TABULATIONS.with(|service| service.borrow_mut().insert(election_id, state));
+        return;
+    }
+
+    let counts: BTreeMap<TallyEntry, RunningTally> = job.counts.iter().cloned().collect();
+    let delegated: BTreeMap<TallyEntry, RunningTally> = job.delegated_counts.iter().cloned().collect();
+    let mut outcome = election_outcome(election, &counts, &delegated, state.clone());
+    if let Some(tie) = outcome.result.tie.clone() {
+        match breaker.decide(TieStage::Winners, &tie) {
+            Some(decision) => break_tie(&mut outcome.result, decision),
+            None if election.tie_break.is_some() => await_draw(job, election.tie_break, TieStage::Winners, tie),
+            None => {}
+        }
+    }
+    // an outcome waiting on its tie-break is stored while the election stays Closed, and its
+    // tabulations kept for when the draw is made
+    do_insert_outcome(election, outcome);
+    if job.status == TallyJobStatus::Tabulating {
+        TABULATIONS.with(|service| service.borrow_mut().remove(&election_id));
+        finish_tally(job, election);
+    } else {
+        TABULATIONS.with(|service| service.borrow_mut().insert(election_id, state));
+        do_insert_election(election);
+    }
+}
+
+// holds a tally job until the tie is drawn, by raw_rand or by an admin as the election's policy says
+fn await_draw(job: &mut TallyJob, policy: Option<TieBreak>, stage: TieStage, tie: Tie) {
+    job.status = if policy == Some(TieBreak::Random) {
+        TallyJobStatus::AwaitingRandomness
+    } else {
+        TallyJobStatus::AwaitingLotDraw
+    };
+    job.pending_tie = Some((stage, tie));
+}
+
+// records a draw for the tie a tally job waits on, and carries on tabulating
+fn record_draw(job: &mut TallyJob, decision: TieBreakDecision) {
+    job.pending_tie = None;
+    job.draws.push(decision);
+    job.status = TallyJobStatus::Tabulating;
+    do_insert_tally_job(job);
+    let election_id = job.election_id;
+    ic_cdk_timers::set_timer(Duration::ZERO, move || run_tally_job(election_id));
+}
+
+// moves a tallied election to Tallied, leaving its job to remove the rankings it counted
+fn finish_tally(job: &mut TallyJob, election: &mut Election) {
+    election.status = ElectionStatus::Tallied;
+    do_insert_election(election);
+    job.status = TallyJobStatus::CleaningUp;
+    job.finished_at = Some(time());
+}
+
+// draws the tie a tally job waits on with randomness from raw_rand
+async fn draw_tied_places(election_id: u64) {
+    match raw_rand().await {
+        Ok((randomness,)) => {
+            let Some(mut job) = _get_tally_job(election_id) else {
+                return;
+            };
+            // a draw requested before an upgrade may complete after another one
+            if job.status != TallyJobStatus::AwaitingRandomness {
+                return;
+            }
+            let Some((stage, tie)) = job.pending_tie.clone() else {
+                return;
+            };
+            let chosen = drawn_by_lot(&tie, &randomness);
+            let decision = TieBreakDecision {
+                stage,
+                tie,
+                basis: TieBreakBasis::Random { randomness },
+                chosen,
+            };
+            record_draw(&mut job, decision);
+        }
+        Err((code, msg)) => {
+            ic_cdk::println!("cannot draw a tie-break: {:?} {}, retrying", code, msg);
+            ic_cdk_timers::set_timer(Duration::from_secs(1), move || run_tally_job(election_id));
+        }
+    }
+}
+
+// records the candidates an admin drew by lot for the tie an election's tally waits on: the tied
+// places given to them, or the candidate elected or eliminated in a round. The tally then carries on.
+#[ic_cdk::update]
+fn record_lot_draw(election_id: u64, drawn: Vec<String>) -> Result<TallyJob, Error> {
+    caller_administered_election(election_id)?;
+    let recorded_by = caller_principal()?;
+
+    let not_waiting = || Error::InvalidTransition {
+        msg: format!("election with id={} is not waiting for a lot draw", election_id),
+    };
+    let mut job = _get_tally_job(election_id)
+        .filter(|job| job.status == TallyJobStatus::AwaitingLotDraw)
+        .ok_or_else(not_waiting)?;
+    let (stage, tie) = job.pending_tie.clone().ok_or_else(not_waiting)?;
+
+    if drawn.len() != tie.places as usize {
+        return Err(Error::InvalidPayload {
+            msg: format!("the draw must fill {} tied places", tie.places),
+        });
+    }
+    for (position, candidate) in drawn.iter().enumerate() {
+        if !tie.candidates.contains(candidate) || drawn[..position].contains(candidate) {
+            return Err(Error::InvalidPayload {
+                msg: format!("'{}' is not a tied candidate left to draw", candidate),
+            });
+        }
+    }
+
+    let decision = TieBreakDecision {
+        stage,
+        tie,
+        basis: TieBreakBasis::LotDraw { recorded_by },
+        chosen: drawn,
+    };
+    record_draw(&mut job, decision);
+    Ok(job)
+}
+
+// passes the chunk of an election's votes from the vote id `from` on to `count`, returning the
+// vote id the next chunk starts at, None once every vote is counted
+fn count_chunk(election_id: u64, from: u64, mut count: impl FnMut(Vote)) -> Option<u64> {
+    let vote_ids: Vec<u64> = VOTES_BY_ELECTION.with(|service| {
+        service
+            .borrow()
+            .range((election_id, from)..=(election_id, u64::MAX))
+            .take(TALLY_CHUNK_SIZE + 1)
+            .map(|((_, vote_id), _)| vote_id)
+            .collect()
+    });
+    for vote in vote_ids.iter().take(TALLY_CHUNK_SIZE).filter_map(_get_vote) {
+        count(vote);
+    }
+    vote_ids.get(TALLY_CHUNK_SIZE).copied()
+}
+
+// counts the next chunk of votes, grouping the rankings in stable memory
+fn count_step(job: &mut TallyJob, election: &Election) {
+    let mut counts: BTreeMap<TallyEntry, RunningTally> = std::mem::take(&mut job.counts).into_iter().collect();
+    let mut last_votes: BTreeMap<String, u64> = std::mem::take(&mut job.last_votes).into_iter().collect();
+    let next = count_chunk(job.election_id, job.next_vote_id, |vote| {
+        add_vote(&mut counts, election, &vote);
+        add_last_vote(&mut last_votes, election, &vote);
+        if let Selection::Ranking(ranking) = vote.selection {
+            add_ranking(election.id, ranking, vote.weight);
+        }
+        job.votes_counted += 1;
+    });
+    job.counts = counts.into_iter().collect();
+    job.last_votes = last_votes.into_iter().collect();
+
+    match next {
+        Some(next_vote_id) => job.next_vote_id = next_vote_id,
+        None => {
+            job.status = TallyJobStatus::ResolvingDelegations;
+            job.next_delegation = Some(first_delegation(election));
+        }
+    }
+}
+
+// follows the next chunk of delegations, counting the weight that reaches a voter on their vote
+// and, for a ranked ballot, on their ranking
+fn resolve_chunk(job: &mut TallyJob, election: &Election) {
+    let (resolved, next) = match job.next_delegation.take() {
+        Some(from) => resolve_delegations_from(election, from, TALLY_CHUNK_SIZE),
+        None => (Vec::new(), None),
+    };
+
+    let mut delegated: BTreeMap<TallyEntry, RunningTally> =
+        std::mem::take(&mut job.delegated_counts).into_iter().collect();
+    for (recipient, weight) in resolved {
+        let Some(vote) = _get_voter_vote(election.id, recipient) else {
+            continue;
+        };
+        add_delegated_vote(&mut delegated, election, &vote, &DelegatedWeight { weight, voters: 1 });
+        if let Selection::Ranking(ranking) = vote.selection {
+            add_ranking(job.election_id, ranking, weight);
+        }
+    }
+    job.delegated_counts = delegated.into_iter().collect();
+
+    job.next_delegation = next;
+    if job.next_delegation.is_none() {
+        job.status = TallyJobStatus::Tabulating;
+        job.next_ranking = Some(Vec::new());
+    }
+}
+
+// adds weight to a ranking counted for an election
+fn add_ranking(election_id: u64, ranking: Vec<String>, weight: u64) {
+    let key = RankingKey { election_id, ranking };
+    TALLY_RANKINGS.with(|service| {
+        let mut service = service.borrow_mut();
+        let cast = service.get(&key).unwrap_or(0);
+        service.insert(key, cast + weight);
+    });
+}
+
+// adds the rankings counted for an election to a tabulation pass from the ranking `from` on,
+// until a message's budget of work is spent, returning the ranking the pass resumes at
+fn read_rankings(
+    election_id: u64,
+    candidates: &[String],
+    tabulation: &mut Tabulation,
+    from: Vec<String>,
+) -> Option<Vec<String>> {
+    let first = RankingKey {
+        election_id,
+        ranking: from,
+    };
+    let mut spent = 0;
+    TALLY_RANKINGS.with(|service| {
+        for (key, cast) in service.borrow().range(first..) {
+            if key.election_id != election_id {
+                break;
+            }
+            if spent >= TABULATION_BUDGET {
+                return Some(key.ranking);
+            }
+            spent += ballot_cost(&key.ranking, candidates);
+            tabulation.add_ballot(candidates, &key.ranking, cast);
+        }
+        None
+    })
+}
+
+// removes the next chunk of the rankings counted for an election, returning whether any are left
+fn remove_rankings(election_id: u64) -> bool {
+    let first = RankingKey {
+        election_id,
+        ranking: Vec::new(),
+    };
+    TALLY_RANKINGS.with(|service| {
+        let mut service = service.borrow_mut();
+        let keys: Vec<RankingKey> = service
+            .range(first..)
+            .take_while(|(key, _)| key.election_id == election_id)
+            .take(TALLY_CHUNK_SIZE + 1)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys.iter().take(TALLY_CHUNK_SIZE) {
+            service.remove(key);
+        }
+        keys.len() > TALLY_CHUNK_SIZE
+    })
+}
+
+// starts recounting an election from its stored votes, a chunk per message. Once every vote is
+// counted its running tallies are replaced with the recount, and the job lists where they differed.
+// A job that stopped making progress can be started again.
+#[ic_cdk::update]
+fn recount_election(election_id: u64) -> Result<RecountJob, Error> {
+    caller_administered_election(election_id)?;
+
+    if let Some(job) = _get_recount_job(election_id) {
+        let stalled = time().saturating_sub(job.updated_at) > TALLY_STALL_TIMEOUT.as_nanos() as u64;
+        if job.finished_at.is_none() && !stalled {
+            return Err(Error::InvalidTransition {
+                msg: format!("election with id={} is already being recounted", election_id),
+            });
+        }
+    }
+
+    let mut job = RecountJob {
+        election_id,
+        votes_counted: 0,
+        started_at: time(),
+        updated_at: time(),
+        finished_at: None,
+        next_vote_id: 0,
+        counts: vec![(TallyEntry::Turnout, RunningTally::default())],
+        mismatches: Vec::new(),
+    };
+    do_insert_recount_job(&mut job);
+    ic_cdk_timers::set_timer(Duration::ZERO, move || run_recount_job(election_id));
+    Ok(job)
+}
+
+#[ic_cdk::query]
+fn get_recount_job(election_id: u64) -> Result<RecountJob, Error> {
+    caller_administered_election(election_id)?;
+    _get_recount_job(election_id).ok_or(Error::NotFound {
+        msg: format!("election with id={} has not been recounted", election_id),
+    })
+}
+
+// recounts the next chunk of votes and schedules the one after, or replaces the running tallies
+// once every vote is counted
+fn run_recount_job(election_id: u64) {
+    let (Some(mut job), Some(election)) = (_get_recount_job(election_id), _get_election(&election_id)) else {
+        return;
+    };
+    if job.finished_at.is_some() {
+        return;
+    }
+
+    // votes may be moving within the election index, as for tally jobs
+    if !votes_indexed() || has_sequential_vote_ids() {
+        ic_cdk_timers::set_timer(Duration::from_secs(1), move || run_recount_job(election_id));
+        return;
+    }
+
+    let mut counts: BTreeMap<TallyEntry, RunningTally> = std::mem::take(&mut job.counts).into_iter().collect();
+    let next = count_chunk(election_id, job.next_vote_id, |vote| {
+        add_vote(&mut counts, &election, &vote);
+        job.votes_counted += 1;
+    });
+
+    match next {
+        Some(next_vote_id) => {
+            job.counts = counts.into_iter().collect();
+            job.next_vote_id = next_vote_id;
+            ic_cdk_timers::set_timer(Duration::ZERO, move || run_recount_job(election_id));
+        }
+        None => {
+            job.mismatches = replace_running_tallies(election_id, counts);
+            job.finished_at = Some(time());
+        }
+    }
+    do_insert_recount_job(&mut job);
+}
+
+// adds a newly cast vote to a running recount of its election that has read past its id, as
+// vote ids are not in the order votes are cast
+pub(crate) fn recount_vote(election: &Election, vote: &Vote) {
+    let Some(mut job) = _get_recount_job(election.id) else {
+        return;
+    };
+    if job.finished_at.is_some() || vote.id >= job.next_vote_id {
+        return;
+    }
+
+    let mut counts: BTreeMap<TallyEntry, RunningTally> = std::mem::take(&mut job.counts).into_iter().collect();
+    add_vote(&mut counts, election, vote);
+    job.counts = counts.into_iter().collect();
+    job.votes_counted += 1;
+    RECOUNT_JOBS.with(|service| service.borrow_mut().insert(election.id, job));
+}
+
+// a helper method to get the tabulation a tally job is in the middle of.
+fn _get_tabulation(election_id: u64) -> Option<TabulationState> {
+    TABULATIONS.with(|service| service.borrow().get(&election_id))
+}
+
+// a helper method to get the tally job of an election.
+fn _get_tally_job(election_id: u64) -> Option<TallyJob> {
+    TALLY_JOBS.with(|service| service.borrow().get(&election_id))
+}
+
+// helper method to perform insert for tally jobs, which marks them as having made progress.
+fn do_insert_tally_job(job: &mut TallyJob) {
+    job.updated_at = time();
+    TALLY_JOBS.with(|service| service.borrow_mut().insert(job.election_id, job.clone()));
+}
+
+// a helper method to get the recount job of an election.
+fn _get_recount_job(election_id: u64) -> Option<RecountJob> {
+    RECOUNT_JOBS.with(|service| service.borrow().get(&election_id))
+}
+
+// helper method to perform insert for recount jobs, which marks them as having made progress.
+fn do_insert_recount_job(job: &mut RecountJob) {
+    job.updated_at = time();
+    RECOUNT_JOBS.with(|service| service.borrow_mut().insert(job.election_id, job.clone()));
+}
@@ -1,82 +1,513 @@
-
+use candid::{Decode, Encode, Principal};
 use ic_cdk::api::time;
-use ic_stable_structures::memory_manager::{VirtualMemory, MemoryId};
-use ic_stable_structures::{StableBTreeMap, Storable, DefaultMemoryImpl};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::cell::RefCell;
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+use crate::election::{_get_election, caller_administered_election, validate_text, ElectionStatus, VotingMethod};
+use crate::migration::{decode_record, encode_record, unknown_version, Versioned};
+use crate::storage::{
+    next_id, Sequence, StablePrincipal, ROLES, VOICE_CREDITS, VOTERS, VOTER_PRINCIPALS, VOTER_WEIGHTS,
+};
+use crate::Error;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Voter {
+    pub(crate) id: u64,
+    pub(crate) principal: Principal,
+    pub(crate) username: String,
+    pub(crate) registered_elections: Vec<u64>,
+}
+
+impl Storable for Voter {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
 
-thread_local! {
-    static USERS: RefCell<StableBTreeMap<u64, User, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-pub struct User {
+// a voter as stored before schema versioning, not yet bound to a principal
+#[derive(candid::CandidType, Deserialize)]
+struct VoterV0 {
     id: u64,
     username: String,
+    registered_elections: Vec<u64>,
+}
+
+impl Versioned for Voter {
+    fn migrate(version: u8, payload: &[u8]) -> Self {
+        match version {
+            0 => {
+                let voter = Decode!(payload, VoterV0).unwrap();
+                // no caller resolves to the anonymous principal, so the voter cannot act again
+                // until an owner rebinds them, but the votes they cast still count
+                Voter {
+                    id: voter.id,
+                    principal: Principal::anonymous(),
+                    username: voter.username,
+                    registered_elections: voter.registered_elections,
+                }
+            }
+            _ => unknown_version(version),
+        }
+    }
+}
+
+// the longest username a voter may pick, in bytes
+const MAX_USERNAME_LEN: usize = 64;
+
+// the most elections a voter may register for
+const MAX_REGISTERED_ELECTIONS: usize = 1000;
+
+#[ic_cdk::update]
+fn register_voter(username: String) -> Result<Voter, Error> {
+    let principal = caller_principal()?;
+    validate_text("usernames", &username, MAX_USERNAME_LEN)?;
+
+    if _get_voter_by_principal(&principal).is_some() {
+        return Err(Error::AlreadyRegistered {
+            msg: format!("principal {} is already registered as a voter", principal),
+        });
+    }
+
+    let voter_id = next_id(Sequence::Voter);
+
+    let voter = Voter {
+        id: voter_id,
+        principal,
+        username,
+        registered_elections: Vec::new(),
+    };
+
+    do_insert_voter(&voter);
+    Ok(voter)
+}
+
+// binds a voter migrated from before voters had principals to the principal they now call with.
+// Owners do this once they have confirmed who the voter is.
+#[ic_cdk::update]
+fn rebind_voter(voter_id: u64, principal: Principal) -> Result<Voter, Error> {
+    caller_owner()?;
+
+    let mut voter = _get_voter(&voter_id).ok_or(Error::NotFound {
+        msg: format!("a voter with id={} not found", voter_id),
+    })?;
+    if voter.principal != Principal::anonymous() {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "voter with id={} is already bound to principal {}",
+                voter_id, voter.principal
+            ),
+        });
+    }
+
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "voters cannot be bound to the anonymous principal".to_string(),
+        });
+    }
+    if _get_voter_by_principal(&principal).is_some() {
+        return Err(Error::AlreadyRegistered {
+            msg: format!("principal {} is already registered as a voter", principal),
+        });
+    }
+
+    voter.principal = principal;
+    do_insert_voter(&voter);
+    Ok(voter)
+}
+
+// sets the weight of each listed principal's vote, e.g. when importing a shareholder roll.
+// Principals may be weighted before they register as voters. Once an election has weights, only
+// the principals given one may vote in it.
+#[ic_cdk::update]
+fn set_voter_weights(election_id: u64, weights: Vec<(Principal, u64)>) -> Result<(), Error> {
+    let election = caller_administered_election(election_id)?;
+
+    if !matches!(
+        election.status,
+        ElectionStatus::Draft | ElectionStatus::RegistrationOpen
+    ) {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "weights of election with id={} are locked once voting opens",
+                election_id
+            ),
+        });
+    }
+
+    if let Some((principal, _)) = weights.iter().find(|(_, weight)| *weight == 0) {
+        return Err(Error::InvalidPayload {
+            msg: format!("principal {} must have a weight of at least 1", principal),
+        });
+    }
+
+    VOTER_WEIGHTS.with(|service| {
+        let mut service = service.borrow_mut();
+        for (principal, weight) in weights {
+            service.insert((election_id, StablePrincipal(principal)), weight);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_voter_weight(election_id: u64, principal: Principal) -> Result<u64, Error> {
+    _get_election(&election_id).ok_or(Error::NotFound {
+        msg: format!("an election with id={} not found", election_id),
+    })?;
+    Ok(principal_weight(election_id, &principal))
+}
+
+#[ic_cdk::update]
+fn register_voter_for_election(election_id: u64) -> Result<(), Error> {
+    let voter = caller_voter()?;
+
+    let election = _get_election(&election_id).ok_or(Error::NotFound {
+        msg: format!("an election with id={} not found", election_id),
+    })?;
+
+    if election.status == ElectionStatus::RegistrationOpen {
+        if voter.registered_elections.len() >= MAX_REGISTERED_ELECTIONS {
+            return Err(Error::CapacityExceeded {
+                msg: format!(
+                    "a voter can register for at most {} elections",
+                    MAX_REGISTERED_ELECTIONS
+                ),
+            });
+        }
+
+        // Only allow registration for elections that haven't started yet
+        let mut updated_voter = voter.clone();
+        updated_voter.registered_elections.push(election_id);
+        do_insert_voter(&updated_voter);
+
+        if election.voting_method == VotingMethod::Quadratic {
+            VOICE_CREDITS.with(|service| {
+                service
+                    .borrow_mut()
+                    .insert((election_id, voter.id), election.voice_credits)
+            });
+        }
+        Ok(())
+    } else {
+        // Election has started; cannot register anymore
+        Err(Error::RegistrationClosed {
+            msg: "cannot register for an election that has already started".to_string(),
+        })
+    }
+}
+
+// returns the calling principal, rejecting anonymous callers.
+pub(crate) fn caller_principal() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous principals are not allowed".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// resolves the voter bound to the calling principal.
+pub(crate) fn caller_voter() -> Result<Voter, Error> {
+    let principal = caller_principal()?;
+    _get_voter_by_principal(&principal).ok_or(Error::NotRegistered {
+        msg: format!("no voter registered for principal {}", principal),
+    })
+}
+
+// helper method to perform insert for voters.
+fn do_insert_voter(voter: &Voter) {
+    VOTERS.with(|service| service.borrow_mut().insert(voter.id, voter.clone()));
+    VOTER_PRINCIPALS.with(|service| service.borrow_mut().insert(StablePrincipal(voter.principal), voter.id));
+}
+
+// a helper method to get the voice credits a voter received for a quadratic election.
+pub(crate) fn _get_voice_credits(election_id: u64, voter_id: u64) -> Option<u64> {
+    VOICE_CREDITS.with(|service| service.borrow().get(&(election_id, voter_id)))
+}
+
+// checks that a voter registered for an election and, if the election has a roll of weights, is on it
+pub(crate) fn validate_eligible(election_id: u64, voter: &Voter) -> Result<(), Error> {
+    if !voter.registered_elections.contains(&election_id) {
+        return Err(Error::NotRegistered {
+            msg: format!(
+                "voter with id={} is not registered for election with id={}",
+                voter.id, election_id
+            ),
+        });
+    }
+
+    let on_roll = |principal: &Principal| {
+        VOTER_WEIGHTS.with(|service| {
+            let service = service.borrow();
+            let first = (election_id, StablePrincipal(Principal::management_canister()));
+            let has_roll = service
+                .range(first..)
+                .next()
+                .is_some_and(|((id, _), _)| id == election_id);
+            !has_roll || service.contains_key(&(election_id, StablePrincipal(*principal)))
+        })
+    };
+    if !on_roll(&voter.principal) {
+        return Err(Error::NotRegistered {
+            msg: format!(
+                "principal {} is not on the roll of election with id={}",
+                voter.principal, election_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// the weight of a principal's vote in an election, 1 unless an admin set another
+fn principal_weight(election_id: u64, principal: &Principal) -> u64 {
+    VOTER_WEIGHTS
+        .with(|service| service.borrow().get(&(election_id, StablePrincipal(*principal))))
+        .unwrap_or(1)
+}
+
+// the weight of a voter's vote in an election
+pub(crate) fn voter_weight(election_id: u64, voter_id: u64) -> u64 {
+    _get_voter(&voter_id)
+        .map(|voter| principal_weight(election_id, &voter.principal))
+        .unwrap_or(1)
+}
+
+// a helper method to get a voter by id.
+pub(crate) fn _get_voter(id: &u64) -> Option<Voter> {
+    VOTERS.with(|service| service.borrow().get(id))
+}
+
+// a helper method to get a voter by the principal they registered with.
+pub(crate) fn _get_voter_by_principal(principal: &Principal) -> Option<Voter> {
+    VOTER_PRINCIPALS
+        .with(|service| service.borrow().get(&StablePrincipal(*principal)))
+        .and_then(|voter_id| _get_voter(&voter_id))
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    // canister-wide; manages roles and creates elections
+    Owner,
+    // may edit and cancel a single election
+    ElectionAdmin,
+    // may view interim results of a single election
+    Observer,
 }
 
-impl Storable for User {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+// a role held by a principal, election_id is None for canister-wide roles
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct RoleKey {
+    pub(crate) principal: Principal,
+    pub(crate) role: Role,
+    pub(crate) election_id: Option<u64>,
+}
+
+impl Storable for RoleKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
 }
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RoleGrant {
+    pub(crate) granted_by: Principal,
+    pub(crate) granted_at: u64,
 }
 
-// Function to register a new user
-pub fn register_user(username: String) -> Option<User> {
-    let user_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
+impl Storable for RoleGrant {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    let user = User {
-        id: user_id,
-        username,
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RoleAssignment {
+    pub(crate) principal: Principal,
+    pub(crate) role: Role,
+    pub(crate) election_id: Option<u64>,
+    pub(crate) granted_by: Principal,
+    pub(crate) granted_at: u64,
+}
+
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role, election_id: Option<u64>) -> Result<(), Error> {
+    let caller = caller_owner()?;
+    validate_role_scope(role, election_id)?;
+    do_insert_role(principal, role, election_id, caller);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role, election_id: Option<u64>) -> Result<(), Error> {
+    let caller = caller_owner()?;
+    validate_role_scope(role, election_id)?;
+
+    // An owner cannot lock everyone out by revoking themselves
+    if role == Role::Owner && principal == caller {
+        return Err(Error::InvalidPayload {
+            msg: "owners cannot revoke their own owner role".to_string(),
+        });
+    }
+
+    let key = RoleKey {
+        principal,
+        role,
+        election_id,
     };
+    match ROLES.with(|service| service.borrow_mut().remove(&key)) {
+        Some(_) => Ok(()),
+        None => Err(Error::NotFound {
+            msg: format!("principal {} does not hold the given role", principal),
+        }),
+    }
+}
 
-    do_insert_user(&user);
-    Some(user)
+#[ic_cdk::query]
+fn get_roles(principal: Principal) -> Result<Vec<RoleAssignment>, Error> {
+    let roles = ROLES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == principal)
+            .map(|(key, grant)| RoleAssignment {
+                principal: key.principal,
+                role: key.role,
+                election_id: key.election_id,
+                granted_by: grant.granted_by,
+                granted_at: grant.granted_at,
+            })
+            .collect()
+    });
+    Ok(roles)
 }
 
-// Function to get a user by ID
-pub fn get_user(user_id: u64) -> Option<User> {
-    USERS.with(|service| service.borrow().get(&user_id))
+// Check that owner roles are canister-wide and election roles name an existing election
+fn validate_role_scope(role: Role, election_id: Option<u64>) -> Result<(), Error> {
+    match (role, election_id) {
+        (Role::Owner, None) => Ok(()),
+        (Role::Owner, Some(_)) => Err(Error::InvalidPayload {
+            msg: "the owner role cannot be scoped to an election".to_string(),
+        }),
+        (_, Some(election_id)) => _get_election(&election_id).map(|_| ()).ok_or(Error::NotFound {
+            msg: format!("an election with id={} not found", election_id),
+        }),
+        (_, None) => Err(Error::InvalidPayload {
+            msg: "election roles must name an election".to_string(),
+        }),
+    }
 }
 
-// Function to check if a user is registered for a specific election
-pub fn is_user_registered_for_election(user_id: u64, election_id: u64) -> bool {
-    USERS
-        .with(|service| {
-            service
-                .borrow()
-                .get(&user_id)
-                .map(|user| is_user_registered_for_election_internal(user, election_id))
-                .unwrap_or(false)
+// returns the calling principal if it is an owner.
+fn caller_owner() -> Result<Principal, Error> {
+    let caller = caller_principal()?;
+    if is_owner(&caller) {
+        Ok(caller)
+    } else {
+        Err(Error::Unauthorized {
+            msg: "only owners can manage roles and rebind voters".to_string(),
         })
+    }
+}
+
+fn has_role(principal: &Principal, role: Role, election_id: Option<u64>) -> bool {
+    let key = RoleKey {
+        principal: *principal,
+        role,
+        election_id,
+    };
+    ROLES.with(|service| service.borrow().contains_key(&key))
 }
 
-// Helper method to perform insert for users
-fn do_insert_user(user: &User) {
-    USERS.with(|service| service.borrow_mut().insert(user.id, user.clone()));
+pub(crate) fn is_owner(principal: &Principal) -> bool {
+    has_role(principal, Role::Owner, None)
 }
 
-// Internal helper method to check if a user is registered for a specific election
-fn is_user_registered_for_election_internal(user: &User, election_id: u64) -> bool {
-    true
+// canisters upgraded from before roles existed have no owner yet
+pub(crate) fn has_owner() -> bool {
+    ROLES.with(|service| service.borrow().keys().any(|key| key.role == Role::Owner))
 }
 
+// owners administer every election
+pub(crate) fn is_election_admin(principal: &Principal, election_id: u64) -> bool {
+    is_owner(principal) || has_role(principal, Role::ElectionAdmin, Some(election_id))
+}
+
+// Check if the caller may see an election's results before it ends
+pub(crate) fn can_observe_election(election_id: u64) -> bool {
+    let caller = ic_cdk::caller();
+    is_election_admin(&caller, election_id) || has_role(&caller, Role::Observer, Some(election_id))
+}
+
+// helper method to perform insert for roles.
+pub(crate) fn do_insert_role(principal: Principal, role: Role, election_id: Option<u64>, granted_by: Principal) {
+    let key = RoleKey {
+        principal,
+        role,
+        election_id,
+    };
+    let grant = RoleGrant {
+        granted_by,
+        granted_at: time(),
+    };
+    ROLES.with(|service| service.borrow_mut().insert(key, grant));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voter(principal: Principal, registered_elections: Vec<u64>) -> Voter {
+        Voter {
+            id: 1,
+            principal,
+            username: "voter".to_string(),
+            registered_elections,
+        }
+    }
+
+    #[test]
+    fn validate_eligible_requires_registration_and_a_place_on_the_roll() {
+        let shareholder = Principal::from_slice(&[1]);
+        let outsider = Principal::from_slice(&[2]);
+        VOTER_WEIGHTS.with(|service| service.borrow_mut().insert((7, StablePrincipal(shareholder)), 10));
+
+        assert!(validate_eligible(7, &voter(shareholder, vec![7])).is_ok());
+        assert!(matches!(
+            validate_eligible(7, &voter(shareholder, Vec::new())),
+            Err(Error::NotRegistered { .. })
+        ));
+        assert!(matches!(
+            validate_eligible(7, &voter(outsider, vec![7])),
+            Err(Error::NotRegistered { .. })
+        ));
+        // an election without a roll takes every registered voter
+        assert!(validate_eligible(8, &voter(outsider, vec![8])).is_ok());
+    }
+}
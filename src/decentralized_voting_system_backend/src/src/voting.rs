@@ -1,21 +1,67 @@
-
+use candid::Decode;
 use ic_cdk::api::time;
-use ic_stable_structures::memory_manager::{VirtualMemory, MemoryId};
-use ic_stable_structures::{StableBTreeMap, Storable, DefaultMemoryImpl};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::cell::RefCell;
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+use crate::ballot_creation::validate_answers;
+use crate::election::{_get_election, is_election_ongoing, validate_text, Election, VotingMethod, MAX_CANDIDATE_LEN};
+use crate::migration::votes_indexed;
+use crate::migration::{decode_record, encode_record, unknown_version, Versioned};
+use crate::result::record_vote;
+use crate::storage::{next_vote_id, VOTES_BY_ELECTION, VOTES_BY_VOTER, VOTES_STORAGE};
+use crate::tally_job::recount_vote;
+use crate::user_management::{_get_voice_credits, caller_voter, validate_eligible, voter_weight, Voter};
+use crate::Error;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Vote {
+    pub(crate) id: u64,
+    pub(crate) voter_id: u64,
+    // the chosen candidate, the first preference of a ranked ballot, or empty for other ballots
+    pub(crate) candidate: String,
+    pub(crate) election_id: u64,
+    pub(crate) timestamp: u64,
+    // set when the candidate is not listed on the election
+    pub(crate) write_in: bool,
+    pub(crate) selection: Selection,
+    // the voter's weight in the election when the vote was cast
+    pub(crate) weight: u64,
+}
 
-thread_local! {
-    static VOTES_STORAGE: RefCell<StableBTreeMap<u64, Vote, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// what a voter marked on their ballot, matching the election's voting method
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum Selection {
+    Candidate(String),
+    // candidates in order of preference, most preferred first
+    Ranking(Vec<String>),
+    // every candidate the voter approves of
+    Approvals(Vec<String>),
+    // a score from 0 to Election.max_score per candidate; unscored candidates get 0
+    Scores(Vec<(String, u32)>),
+    // votes given to each candidate, costing votes squared voice credits
+    Allocations(Vec<(String, u32)>),
+    // the option picked on each open ballot of a referendum, by ballot id
+    Answers(Vec<(u64, String)>),
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-pub struct Vote {
+// a trait that must be implemented for a struct that is stored in a stable struct
+impl Storable for Vote {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// a vote as stored before schema versioning, always for a single listed candidate
+#[derive(candid::CandidType, Deserialize)]
+struct VoteV0 {
     id: u64,
     voter_id: u64,
     candidate: String,
@@ -23,79 +69,294 @@ pub struct Vote {
     timestamp: u64,
 }
 
-impl Storable for Vote {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+impl Versioned for Vote {
+    fn migrate(version: u8, payload: &[u8]) -> Self {
+        match version {
+            0 => {
+                let vote = Decode!(payload, VoteV0).unwrap();
+                Vote {
+                    id: vote.id,
+                    voter_id: vote.voter_id,
+                    selection: Selection::Candidate(vote.candidate.clone()),
+                    candidate: vote.candidate,
+                    election_id: vote.election_id,
+                    timestamp: vote.timestamp,
+                    write_in: false,
+                    weight: 1,
+                }
+            }
+            _ => unknown_version(version),
+        }
     }
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct VotePayload {
+    pub(crate) selection: Selection,
+    pub(crate) election_id: u64,
+}
+
+#[ic_cdk::query]
+fn get_vote(id: u64) -> Result<Vote, Error> {
+    match _get_vote(&id) {
+        Some(vote) => Ok(vote),
+        None => Err(Error::NotFound {
+            msg: format!("a vote with id={} not found", id),
+        }),
     }
 }
 
-impl BoundedStorable for Vote {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
-}
-
-// Function to cast a vote
-pub fn cast_vote(payload: VotePayload) -> Option<Vote> {
-    // Validate if the election is still open for voting
-    if is_election_open(payload.election_id) {
-        // Check if the user has already cast a vote in this election
-        if !has_user_cast_vote(payload.voter_id, payload.election_id) {
-            let vote_id = ID_COUNTER
-                .with(|counter| {
-                    let current_value = *counter.borrow().get();
-                    counter.borrow_mut().set(current_value + 1)
-                })
-                .expect("cannot increment id counter");
-
-            let vote = Vote {
-                id: vote_id,
-                voter_id: payload.voter_id,
-                candidate: payload.candidate,
-                election_id: payload.election_id,
-                timestamp: time(),
-            };
-
-            do_insert_vote(&vote);
-            Some(vote)
-        } else {
-            // User has already cast a vote in this election
-            None
+#[ic_cdk::update]
+fn cast_vote(vote_payload: VotePayload) -> Result<Vote, Error> {
+    let voter = caller_voter()?;
+    do_cast_vote(&voter, vote_payload)
+}
+
+// records a vote on behalf of an already resolved voter.
+fn do_cast_vote(voter: &Voter, vote_payload: VotePayload) -> Result<Vote, Error> {
+    let voter_id = voter.id;
+    // Validate that the election is ongoing
+    if is_election_ongoing(vote_payload.election_id) {
+        // Only registered voters on the election's roll may vote
+        validate_eligible(vote_payload.election_id, voter)?;
+
+        // Only one vote per voter in each election
+        if has_voter_voted(vote_payload.election_id, voter_id) {
+            return Err(Error::AlreadyVoted {
+                msg: format!(
+                    "voter with id={} has already voted in election with id={}",
+                    voter_id, vote_payload.election_id
+                ),
+            });
         }
+
+        let election = _get_election(&vote_payload.election_id).ok_or(Error::NotFound {
+            msg: format!("an election with id={} not found", vote_payload.election_id),
+        })?;
+        let (candidate, write_in) = validate_selection(&election, &vote_payload.selection)?;
+
+        if let Selection::Allocations(allocations) = &vote_payload.selection {
+            let credits = _get_voice_credits(election.id, voter_id).ok_or(Error::NotRegistered {
+                msg: format!(
+                    "voter with id={} has no voice credits in election with id={}",
+                    voter_id, election.id
+                ),
+            })?;
+            let cost = quadratic_cost(allocations);
+            if cost > credits {
+                return Err(Error::InsufficientCredits {
+                    msg: format!("ballot costs {} voice credits but only {} are available", cost, credits),
+                });
+            }
+        }
+
+        // the key is seeded right after install, before any election can open
+        let id = next_vote_id().ok_or(Error::VotingClosed {
+            msg: "votes are accepted once vote ids are seeded, try again shortly".to_string(),
+        })?;
+
+        let vote = Vote {
+            id,
+            voter_id,
+            candidate,
+            election_id: vote_payload.election_id,
+            timestamp: time(),
+            write_in,
+            selection: vote_payload.selection,
+            weight: voter_weight(election.id, voter_id),
+        };
+        do_insert_vote(&vote);
+        record_vote(&election, &vote);
+        recount_vote(&election, &vote);
+        Ok(vote)
     } else {
-        // Election is closed for voting
-        None
+        Err(Error::VotingClosed {
+            msg: format!("election with id={} is not ongoing", vote_payload.election_id),
+        })
     }
 }
 
-// Function to get a vote by ID
-pub fn get_vote(vote_id: u64) -> Option<Vote> {
-    VOTES_STORAGE.with(|service| service.borrow().get(&vote_id))
+// Check that a selection fits the election's voting method and lists only its candidates,
+// returning the candidate recorded on the vote and whether it is a write-in
+fn validate_selection(election: &Election, selection: &Selection) -> Result<(String, bool), Error> {
+    match (election.voting_method, selection) {
+        (VotingMethod::Plurality, Selection::Candidate(candidate)) => {
+            if is_listed_candidate(election, candidate) {
+                Ok((candidate.clone(), false))
+            } else if election.allow_write_ins && !candidate.trim().is_empty() {
+                validate_text("candidate names", candidate, MAX_CANDIDATE_LEN)?;
+                Ok((candidate.clone(), true))
+            } else {
+                Err(invalid_candidate(election, candidate))
+            }
+        }
+        (
+            VotingMethod::RankedChoice | VotingMethod::SingleTransferableVote | VotingMethod::Borda,
+            Selection::Ranking(ranking),
+        ) => {
+            if ranking.is_empty() {
+                return Err(Error::InvalidPayload {
+                    msg: "a ranked ballot must rank at least one candidate".to_string(),
+                });
+            }
+            for (position, candidate) in ranking.iter().enumerate() {
+                if !is_listed_candidate(election, candidate) {
+                    return Err(invalid_candidate(election, candidate));
+                }
+                if ranking[..position].contains(candidate) {
+                    return Err(Error::InvalidPayload {
+                        msg: format!("'{}' is ranked more than once", candidate),
+                    });
+                }
+            }
+            Ok((ranking[0].clone(), false))
+        }
+        (VotingMethod::Approval, Selection::Approvals(approvals)) => {
+            if approvals.is_empty() {
+                return Err(Error::InvalidPayload {
+                    msg: "an approval ballot must approve at least one candidate".to_string(),
+                });
+            }
+            for (position, candidate) in approvals.iter().enumerate() {
+                if !is_listed_candidate(election, candidate) {
+                    return Err(invalid_candidate(election, candidate));
+                }
+                if approvals[..position].contains(candidate) {
+                    return Err(Error::InvalidPayload {
+                        msg: format!("'{}' is approved more than once", candidate),
+                    });
+                }
+            }
+            Ok((String::new(), false))
+        }
+        (VotingMethod::Quadratic, Selection::Allocations(allocations)) => {
+            for (position, (candidate, _)) in allocations.iter().enumerate() {
+                if !is_listed_candidate(election, candidate) {
+                    return Err(invalid_candidate(election, candidate));
+                }
+                if allocations[..position]
+                    .iter()
+                    .any(|(allocated, _)| allocated == candidate)
+                {
+                    return Err(Error::InvalidPayload {
+                        msg: format!("'{}' is allocated votes more than once", candidate),
+                    });
+                }
+            }
+            Ok((String::new(), false))
+        }
+        (VotingMethod::Referendum, Selection::Answers(answers)) => {
+            validate_answers(election, answers)?;
+            Ok((String::new(), false))
+        }
+        (VotingMethod::Score, Selection::Scores(scores)) => {
+            for (position, (candidate, score)) in scores.iter().enumerate() {
+                if !is_listed_candidate(election, candidate) {
+                    return Err(invalid_candidate(election, candidate));
+                }
+                if scores[..position].iter().any(|(scored, _)| scored == candidate) {
+                    return Err(Error::InvalidPayload {
+                        msg: format!("'{}' is scored more than once", candidate),
+                    });
+                }
+                if *score > election.max_score {
+                    return Err(Error::InvalidPayload {
+                        msg: format!(
+                            "'{}' is scored {} but the highest allowed score is {}",
+                            candidate, score, election.max_score
+                        ),
+                    });
+                }
+            }
+            Ok((String::new(), false))
+        }
+        (voting_method, _) => Err(Error::InvalidPayload {
+            msg: format!("election with id={} expects a {:?} ballot", election.id, voting_method),
+        }),
+    }
+}
+
+// the voice credits a quadratic ballot spends
+fn quadratic_cost(allocations: &[(String, u32)]) -> u64 {
+    allocations
+        .iter()
+        .map(|(_, votes)| *votes as u64 * *votes as u64)
+        .fold(0, u64::saturating_add)
+}
+
+fn is_listed_candidate(election: &Election, candidate: &str) -> bool {
+    election.candidates.iter().any(|listed| listed == candidate)
+}
+
+fn invalid_candidate(election: &Election, candidate: &str) -> Error {
+    Error::InvalidCandidate {
+        msg: format!("'{}' is not a candidate in election with id={}", candidate, election.id),
+    }
+}
+
+// helper method to perform insert for votes.
+pub(crate) fn do_insert_vote(vote: &Vote) {
+    VOTES_STORAGE.with(|service| service.borrow_mut().insert(vote.id, vote.clone()));
+    VOTES_BY_VOTER.with(|service| service.borrow_mut().insert((vote.election_id, vote.voter_id), vote.id));
+    VOTES_BY_ELECTION.with(|service| service.borrow_mut().insert((vote.election_id, vote.id), ()));
 }
 
-// Internal helper method to check if a user has already cast a vote in a specific election
-fn has_user_cast_vote(voter_id: u64, election_id: u64) -> bool {
-    VOTES_STORAGE
-        .with(|service| {
+// removes a vote and its election index entry, leaving VOTES_BY_VOTER to whoever replaces it
+pub(crate) fn do_remove_vote(vote: &Vote) {
+    VOTES_STORAGE.with(|service| service.borrow_mut().remove(&vote.id));
+    VOTES_BY_ELECTION.with(|service| service.borrow_mut().remove(&(vote.election_id, vote.id)));
+}
+
+// a helper method to get the votes cast in an election. Until the election index has been built
+// after an upgrade, every vote is scanned instead.
+pub(crate) fn _get_election_votes(election_id: u64) -> Vec<Vote> {
+    if !votes_indexed() {
+        return VOTES_STORAGE.with(|service| {
             service
                 .borrow()
-                .values()
-                .any(|vote| vote.voter_id == voter_id && vote.election_id == election_id)
-        })
+                .iter()
+                .map(|(_, vote)| vote)
+                .filter(|vote| vote.election_id == election_id)
+                .collect()
+        });
+    }
+
+    let vote_ids: Vec<u64> = VOTES_BY_ELECTION.with(|service| {
+        service
+            .borrow()
+            .range((election_id, 0)..=(election_id, u64::MAX))
+            .map(|((_, vote_id), _)| vote_id)
+            .collect()
+    });
+    VOTES_STORAGE.with(|service| {
+        let service = service.borrow();
+        vote_ids.iter().filter_map(|vote_id| service.get(vote_id)).collect()
+    })
 }
 
-// Internal helper method to check if an election is still open for voting
-fn is_election_open(election_id: u64) -> bool {
-    // Placeholder logic - you might have a more sophisticated mechanism to check election status
-    // For now, assume all elections are always open
-    true
+// Check if a voter has already cast a vote in an election
+pub(crate) fn has_voter_voted(election_id: u64, voter_id: u64) -> bool {
+    VOTES_BY_VOTER.with(|service| service.borrow().contains_key(&(election_id, voter_id)))
 }
 
-// Helper method to perform insert for votes
-fn do_insert_vote(vote: &Vote) {
-    VOTES_STORAGE.with(|service| service.borrow_mut().insert(vote.id, vote.clone()));
+// a helper method to get the vote a voter cast in an election.
+pub(crate) fn _get_voter_vote(election_id: u64, voter_id: u64) -> Option<Vote> {
+    VOTES_BY_VOTER
+        .with(|service| service.borrow().get(&(election_id, voter_id)))
+        .and_then(|vote_id| _get_vote(&vote_id))
 }
 
+// a helper method to get a vote by id. used in get_vote/delete_vote
+pub(crate) fn _get_vote(id: &u64) -> Option<Vote> {
+    VOTES_STORAGE.with(|service| service.borrow().get(id))
+}
+
+#[ic_cdk::update]
+fn cast_vote_for_registered_voter(election_id: u64, candidate: String) -> Result<Vote, Error> {
+    let voter = caller_voter()?;
+    let payload = VotePayload {
+        selection: Selection::Candidate(candidate),
+        election_id,
+    };
+    do_cast_vote(&voter, payload)
+}